@@ -176,7 +176,7 @@ impl SchemeSync for VirtiofsScheme {
         if let Some(res) = self.files.remove(&id) {
             match res {
                 VirtiofsResource::File { inode, fh, .. } => {
-                    let _ = self.proto.release(inode, fh);
+                    let _ = self.proto.release(inode, fh, 0, 0);
                 }
                 VirtiofsResource::Dir { inode, fh, .. } => {
                     let _ = self.proto.releasedir(inode, fh);
@@ -1,20 +1,204 @@
 // FUSE protocol manager (unique counter, send/recv)
 
 mod headers;
-// Todo: make this not pub
-// This shouldnt be pub technically, but,
-// I just want to get rid of the warnings for now - when I do cargo check
+mod notify;
 pub mod opcodes;
 mod structs;
 
+pub use self::notify::FuseNotification;
+pub use self::structs::FUSE_POLL_SCHEDULE_NOTIFY;
+pub use self::structs::{FALLOC_FL_KEEP_SIZE, FALLOC_FL_PUNCH_HOLE, FALLOC_FL_ZERO_RANGE};
+pub use self::structs::{
+    FATTR_ATIME, FATTR_ATIME_NOW, FATTR_CTIME, FATTR_FH, FATTR_GID, FATTR_KILL_SUIDGID,
+    FATTR_LOCKOWNER, FATTR_MODE, FATTR_MTIME, FATTR_MTIME_NOW, FATTR_SIZE, FATTR_UID,
+};
+pub use self::structs::{
+    FUSE_IOCTL_32BIT, FUSE_IOCTL_COMPAT, FUSE_IOCTL_COMPAT_X32, FUSE_IOCTL_DIR,
+    FUSE_IOCTL_UNRESTRICTED,
+};
+pub use self::structs::{FUSE_KERNEL_MINOR_VERSION, FUSE_KERNEL_VERSION, FuseInitFlags};
+pub use self::structs::{FUSE_LK_FLOCK, FUSE_RELEASE_FLOCK_UNLOCK};
+pub use self::structs::{FuseAttr, FuseCopyFileRangeIn, FuseFileLock, FuseSetattrIn};
+pub use self::structs::{RENAME_EXCHANGE, RENAME_NOREPLACE, RENAME_WHITEOUT};
+pub use self::structs::{XATTR_CREATE, XATTR_REPLACE};
+
 use self::headers::*;
-use self::opcodes::*;
+use self::opcodes::FuseOpcode;
 use self::structs::*;
 use crate::transport::common::FuseTransport;
+use crate::util::error::{FuseError, errno_of};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+/// How often GETXATTR/LISTXATTR re-probe when the value grows between the
+/// size probe and the fetch.
+const XATTR_RETRIES: usize = 3;
+
+/// Largest WRITE payload we assume before INIT tells us otherwise.
+const DEFAULT_MAX_WRITE: u32 = 4096;
+
+/// Pages per request when the server does not negotiate MAX_PAGES
+/// (FUSE_DEFAULT_MAX_PAGES_PER_REQ).
+const DEFAULT_MAX_PAGES: u16 = 32;
+
+/// The page `max_pages` counts in.
+const PAGE_SIZE: u32 = 4096;
+
+/// What `send_init` asks for: the capabilities this client makes use of.
+/// ATOMIC_O_TRUNC because OPEN passes O_TRUNC through; without it the
+/// file is truncated with SETATTR instead.
+pub const DEFAULT_INIT_FLAGS: FuseInitFlags = FuseInitFlags::POSIX_LOCKS
+    .union(FuseInitFlags::ATOMIC_O_TRUNC)
+    .union(FuseInitFlags::BIG_WRITES)
+    .union(FuseInitFlags::FLOCK_LOCKS)
+    .union(FuseInitFlags::DO_READDIRPLUS)
+    .union(FuseInitFlags::READDIRPLUS_AUTO)
+    .union(FuseInitFlags::MAX_PAGES);
+
+/// Nodes per BATCH_FORGET message; keeps each one at about a page.
+const FORGET_BATCH: usize = 256;
+
+/// How long a request may take before it is interrupted.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long an interrupted request gets to answer before we give up on it.
+/// Its reply is dropped whenever it turns up later.
+const INTERRUPT_GRACE: Duration = Duration::from_secs(1);
+
+/// Pause before re-sending an INTERRUPT the server answered with EAGAIN.
+const INTERRUPT_RETRY: Duration = Duration::from_millis(10);
+
+/// Longest single wait, so a Ctrl-C that races with poll() is still noticed.
+const CANCEL_POLL: Duration = Duration::from_millis(100);
+
+/// Notifications kept for `take_notifications`; older ones are dropped.
+const NOTIFY_QUEUE_MAX: usize = 1024;
+
+/// Re-poll interval for transports that cannot deliver FUSE_NOTIFY_POLL.
+/// It doubles after every round without events, up to POLL_FALLBACK_MAX.
+const POLL_FALLBACK: Duration = Duration::from_millis(50);
+const POLL_FALLBACK_MAX: Duration = Duration::from_secs(1);
+
+/// RETRY rounds we answer for one unrestricted ioctl; each round lets the
+/// server follow one more level of pointers inside the argument.
+const IOCTL_RETRIES: usize = 8;
+
+// asm-generic ioctl number layout: nr:8 type:8 size:14 dir:2
+const IOC_SIZESHIFT: u32 = 16;
+const IOC_SIZEMASK: u32 = (1 << 14) - 1;
+const IOC_DIRSHIFT: u32 = 30;
+const IOC_WRITE: u32 = 1;
+const IOC_READ: u32 = 2;
+
+/// Set in the unique of an INTERRUPT (FUSE_INT_REQ_BIT); other requests use
+/// even uniques, so `unique | FUSE_INT_REQ_BIT` names the interrupt for it.
+const FUSE_INT_REQ_BIT: u64 = 1;
+
+/// What INIT settled on. Fields the server did not negotiate hold the
+/// values the kernel would assume.
+#[derive(Debug, Clone, Copy)]
+pub struct SessionInfo {
+    pub major: u32,
+    /// Protocol minor version both sides speak.
+    pub minor: u32,
+    /// Capabilities we asked for and the server accepted.
+    pub flags: FuseInitFlags,
+    pub max_readahead: u32,
+    pub max_background: u16,
+    pub congestion_threshold: u16,
+    /// WRITE payloads are split to fit.
+    pub max_write: u32,
+    /// Timestamp granularity in ns; 0 if unknown.
+    pub time_gran: u32,
+    pub max_pages: u16,
+    /// log2 of the DAX mapping alignment; 0 without MAP_ALIGNMENT.
+    pub map_alignment: u16,
+    /// 0 without PASSTHROUGH.
+    pub max_stack_depth: u32,
+}
+
+impl Default for SessionInfo {
+    /// Before INIT: nothing negotiated.
+    fn default() -> Self {
+        Self {
+            major: FUSE_KERNEL_VERSION,
+            minor: 0,
+            flags: FuseInitFlags::empty(),
+            max_readahead: 0,
+            max_background: 0,
+            congestion_threshold: 0,
+            max_write: DEFAULT_MAX_WRITE,
+            time_gran: 0,
+            max_pages: DEFAULT_MAX_PAGES,
+            map_alignment: 0,
+            max_stack_depth: 0,
+        }
+    }
+}
+
+impl SessionInfo {
+    /// Interprets an INIT reply to a request for `requested`.
+    fn negotiate(requested: FuseInitFlags, out: &FuseInitOut) -> Self {
+        let mut offered = FuseInitFlags::from_wire(out.flags, 0);
+        if offered.contains(FuseInitFlags::INIT_EXT) {
+            offered = FuseInitFlags::from_wire(out.flags, out.flags2);
+        }
+        let flags = offered & requested;
+
+        let mut info = Self {
+            major: out.major,
+            minor: out.minor,
+            flags,
+            max_readahead: out.max_readahead,
+            max_background: out.max_background,
+            congestion_threshold: out.congestion_threshold,
+            ..Self::default()
+        };
+
+        if out.max_write > 0 {
+            info.max_write = out.max_write;
+        }
+        if out.minor >= 23 {
+            info.time_gran = out.time_gran;
+        }
+        if flags.contains(FuseInitFlags::MAX_PAGES) {
+            info.max_pages = out.max_pages.max(1);
+        }
+        if flags.contains(FuseInitFlags::MAP_ALIGNMENT) {
+            info.map_alignment = out.map_alignment;
+        }
+        if flags.contains(FuseInitFlags::PASSTHROUGH) {
+            info.max_stack_depth = out.max_stack_depth;
+        }
+
+        info
+    }
+
+    pub fn has(&self, flag: FuseInitFlags) -> bool {
+        self.flags.contains(flag)
+    }
+}
 
 pub struct FuseProtocol<T: FuseTransport> {
     stream: T,
     next_unique: u64,
+    /// Negotiated by INIT; defaults until then.
+    session: SessionInfo,
+    /// Deadline for a reply; `None` waits forever.
+    timeout: Option<Duration>,
+    /// Set (e.g. from a SIGINT handler) to interrupt the request in flight.
+    cancel: Option<&'static AtomicBool>,
+    /// Server answered INTERRUPT with ENOSYS; stop sending them.
+    no_interrupt: bool,
+    /// DESTROY was sent; everything after it fails with ENOTCONN.
+    destroyed: bool,
+    /// Poll handles the server has woken since they were last polled.
+    poll_wakeups: HashSet<u64>,
+    /// Next re-poll interval per poll handle, on transports without `recv`.
+    poll_backoff: HashMap<u64, Duration>,
+    /// Notifications received but not yet taken, oldest first.
+    notifications: VecDeque<FuseNotification>,
 }
 
 impl<T: FuseTransport> FuseProtocol<T> {
@@ -22,92 +206,353 @@ impl<T: FuseTransport> FuseProtocol<T> {
         Self {
             stream,
             next_unique: 2,
+            session: SessionInfo::default(),
+            timeout: Some(DEFAULT_TIMEOUT),
+            cancel: None,
+            no_interrupt: false,
+            destroyed: false,
+            poll_wakeups: HashSet::new(),
+            poll_backoff: HashMap::new(),
+            notifications: VecDeque::new(),
         }
     }
 
-    fn alloc_unique(&mut self) -> u64 {
-        let u = self.next_unique;
-        self.next_unique += 1;
-        u
+    /// How long to wait for a reply before interrupting the request.
+    /// SETLKW always waits until the lock is granted or it is cancelled.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    /// Storing `true` in `flag` interrupts the request in flight, or the
+    /// next one if none is. The flag is cleared once acted on.
+    pub fn set_cancel_flag(&mut self, flag: &'static AtomicBool) {
+        self.cancel = Some(flag);
+    }
+
+    pub fn session(&self) -> &SessionInfo {
+        &self.session
+    }
+
+    pub fn max_write(&self) -> u32 {
+        self.session.max_write
     }
 
-    fn errno_name(code: i32) -> &'static str {
-        match code {
-            libc::ENOENT => "ENOENT (No such file or directory)",
-            libc::EACCES => "EACCES (Permission denied)",
-            libc::EEXIST => "EEXIST (File exists)",
-            libc::ENOSYS => "ENOSYS (Function not implemented)",
-            libc::EROFS => "EROFS (Read-only filesystem)",
-            libc::ENOTDIR => "ENOTDIR (Not a directory)",
-            libc::EISDIR => "EISDIR (Is a directory)",
-            libc::EINVAL => "EINVAL (Invalid argument)",
-            libc::EPERM => "EPERM (Operation not permitted)",
-            libc::ENOTEMPTY => "ENOTEMPTY (Directory not empty)",
-            _ => "Unknown error",
+    /// Largest READ we send: `max_pages` pages, and no more than fits in a
+    /// reply the transport can take.
+    pub fn max_read(&self) -> u32 {
+        let pages = self.session.max_pages as u32 * PAGE_SIZE;
+        match self.stream.max_message() {
+            Some(max) => {
+                let room = max.saturating_sub(std::mem::size_of::<FuseOutHeader>());
+                pages.min(room.try_into().unwrap_or(u32::MAX))
+            }
+            None => pages,
         }
     }
 
-    /// Internal helper to send a FUSE request and receive its reply.
+    /// Whether INIT negotiated FUSE_DO_READDIRPLUS.
+    pub fn readdirplus_supported(&self) -> bool {
+        self.session.has(FuseInitFlags::DO_READDIRPLUS)
+    }
+
+    fn alloc_unique(&mut self) -> u64 {
+        let u = self.next_unique;
+        self.next_unique += 2; // odd uniques are for INTERRUPT
+        u
+    }
+
+    // Internal helper to send a FUSE request and receive its reply.
     // fn send_and_recv(&mut self, req: &[u8]) -> io::Result<Vec<u8>> {
     //     self.stream.roundtrip(req)
     // }
 
     pub fn send_request(
         &mut self,
-        opcode: u32,
+        opcode: FuseOpcode,
+        nodeid: u64,
+        payload: &[u8],
+    ) -> std::io::Result<(FuseOutHeader, Vec<u8>)> {
+        self.send_request_timeout(opcode, nodeid, payload, self.timeout)
+    }
+
+    /// `send_request` with its own deadline. When it passes, or the cancel
+    /// flag is set, the request is interrupted: that fails with ETIMEDOUT or
+    /// EINTR unless the server still completes it within INTERRUPT_GRACE.
+    pub fn send_request_timeout(
+        &mut self,
+        opcode: FuseOpcode,
         nodeid: u64,
         payload: &[u8],
+        timeout: Option<Duration>,
     ) -> std::io::Result<(FuseOutHeader, Vec<u8>)> {
+        if self.destroyed {
+            return Err(FuseError::new(libc::ENOTCONN).into_io());
+        }
+
         // 1) Build fuse_in_header
         let unique = self.alloc_unique();
-        let header = FuseInHeader::new(opcode, nodeid, unique, payload.len());
+        let header = FuseInHeader::new(opcode as u32, nodeid, unique, payload.len());
 
         let header_bytes = bytemuck::bytes_of(&header); // &[u8]
-        let payload = payload; // &[u8]
 
         let mut msg = header_bytes.to_vec(); // Vec<u8>
         msg.extend_from_slice(payload);
 
         // 3) Send/recv raw data
-        let raw = self.stream.roundtrip(&msg)?;
+        let raw = if self.stream.supports_recv() {
+            if self.take_cancel() {
+                return Err(FuseError::new(libc::EINTR).into_io());
+            }
+            self.stream.send(&msg)?;
+            self.wait_reply(unique, timeout)?
+        } else {
+            self.stream.roundtrip(&msg)?
+        };
 
         // 5) Parse fuse_out_header
         let (out_hdr, payload_bytes) = FuseOutHeader::parse(&raw)?;
 
         if out_hdr.error != 0 {
-            let errno = -out_hdr.error;
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                format!("{} ({})", Self::errno_name(errno), errno),
-            ));
+            return Err(FuseError::new(-out_hdr.error).into_io());
         }
 
         // 6) Return header + payload
         Ok((out_hdr, payload_bytes.to_vec()))
     }
 
-    pub fn send_init(&mut self) -> std::io::Result<FuseInitOut> {
-        let init_in = FuseInitIn::new();
+    fn take_cancel(&self) -> bool {
+        self.cancel.is_some_and(|c| c.swap(false, Ordering::SeqCst))
+    }
+
+    fn wait_reply(&mut self, unique: u64, timeout: Option<Duration>) -> std::io::Result<Vec<u8>> {
+        let deadline = timeout.map(|t| Instant::now() + t);
+
+        loop {
+            let now = Instant::now();
+            let timed_out = deadline.is_some_and(|d| now >= d);
+            if timed_out || self.take_cancel() {
+                return self.interrupt(unique, timed_out);
+            }
+
+            let wait = deadline.map_or(CANCEL_POLL, |d| (d - now).min(CANCEL_POLL));
+            let Some(raw) = self.stream.recv(Some(wait))? else {
+                continue;
+            };
+
+            let (hdr, _) = FuseOutHeader::parse(&raw)?;
+            if hdr.unique == unique {
+                return Ok(raw);
+            }
+            self.unsolicited(&raw)?;
+        }
+    }
+
+    /// Sends INTERRUPT for `unique` and waits INTERRUPT_GRACE for the
+    /// request to finish. Returns its reply if it does.
+    fn interrupt(&mut self, unique: u64, timed_out: bool) -> std::io::Result<Vec<u8>> {
+        let errno = if timed_out {
+            libc::ETIMEDOUT
+        } else {
+            libc::EINTR
+        };
+
+        if !self.no_interrupt {
+            self.send_interrupt(unique)?;
+        }
+
+        let deadline = Instant::now() + INTERRUPT_GRACE;
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            let Some(raw) = self.stream.recv(Some(deadline - now))? else {
+                continue;
+            };
+
+            let (hdr, _) = FuseOutHeader::parse(&raw)?;
+            if hdr.unique == unique {
+                // Report a timeout as such, not as the EINTR it ended with.
+                if timed_out && hdr.error == -libc::EINTR {
+                    return Err(FuseError::new(errno).into_io());
+                }
+                return Ok(raw);
+            }
+
+            if hdr.unique == unique | FUSE_INT_REQ_BIT {
+                match -hdr.error {
+                    // Not ready to be interrupted yet; ask again.
+                    libc::EAGAIN => {
+                        std::thread::sleep(INTERRUPT_RETRY);
+                        self.send_interrupt(unique)?;
+                    }
+                    libc::ENOSYS => self.no_interrupt = true,
+                    _ => {}
+                }
+                continue;
+            }
+
+            self.unsolicited(&raw)?;
+        }
+
+        Err(FuseError::new(errno).into_io())
+    }
+
+    /// Handles a message that is not the reply being waited for: a
+    /// notification (unique 0), a late reply to something we gave up on, or
+    /// an answer to an INTERRUPT that no longer matters.
+    fn unsolicited(&mut self, raw: &[u8]) -> std::io::Result<()> {
+        let (hdr, payload) = FuseOutHeader::parse(raw)?;
+
+        if hdr.unique != 0 {
+            return Ok(());
+        }
+
+        // A notification we cannot decode must not fail the request we are
+        // waiting for; drop it.
+        let Ok(notification) = FuseNotification::parse(hdr.error, payload) else {
+            return Ok(());
+        };
+
+        match notification {
+            // Only `wait_poll_wakeup` cares about these.
+            FuseNotification::Poll { kh } => {
+                self.poll_wakeups.insert(kh);
+                return Ok(());
+            }
+            // We keep no page cache, so there is never anything to return.
+            // A reply that cannot be sent is not the business of the request
+            // we are waiting for; the server just never hears back.
+            FuseNotification::Retrieve {
+                notify_unique,
+                nodeid,
+                offset,
+                ..
+            } => {
+                let _ = self.notify_reply(notify_unique, nodeid, offset, &[]);
+            }
+            _ => {}
+        }
+
+        if self.notifications.len() == NOTIFY_QUEUE_MAX {
+            self.notifications.pop_front();
+        }
+        self.notifications.push_back(notification);
+        Ok(())
+    }
+
+    /// Answers FUSE_NOTIFY_RETRIEVE `notify_unique` with `data`, the cached
+    /// bytes of `nodeid` at `offset`. The server sends no reply.
+    fn notify_reply(
+        &mut self,
+        notify_unique: u64,
+        nodeid: u64,
+        offset: u64,
+        data: &[u8],
+    ) -> std::io::Result<()> {
+        let input = FuseNotifyRetrieveIn {
+            dummy1: 0,
+            offset,
+            size: data.len() as u32,
+            dummy2: 0,
+            dummy3: 0,
+            dummy4: 0,
+        };
+        let mut payload = bytemuck::bytes_of(&input).to_vec();
+        payload.extend_from_slice(data);
+
+        let header = FuseInHeader::new(
+            FuseOpcode::NotifyReply as u32,
+            nodeid,
+            notify_unique,
+            payload.len(),
+        );
+        let mut msg = bytemuck::bytes_of(&header).to_vec();
+        msg.extend_from_slice(&payload);
+
+        self.stream.send(&msg)
+    }
+
+    /// Reads whatever the server has sent without waiting, then hands over
+    /// every notification received so far, oldest first. Poll wakeups are
+    /// not included; RETRIEVE has already been answered.
+    pub fn take_notifications(&mut self) -> std::io::Result<Vec<FuseNotification>> {
+        if self.stream.supports_recv() {
+            while let Some(raw) = self.stream.recv(Some(Duration::ZERO))? {
+                self.unsolicited(&raw)?;
+            }
+        }
+
+        Ok(self.notifications.drain(..).collect())
+    }
+
+    fn send_interrupt(&mut self, unique: u64) -> std::io::Result<()> {
+        let input = FuseInterruptIn { unique };
+        let payload = bytemuck::bytes_of(&input);
+
+        let header = FuseInHeader::new(
+            FuseOpcode::Interrupt as u32,
+            0,
+            unique | FUSE_INT_REQ_BIT,
+            payload.len(),
+        );
+        let mut msg = bytemuck::bytes_of(&header).to_vec();
+        msg.extend_from_slice(payload);
+
+        self.stream.send(&msg)
+    }
+
+    /// Sends a request that has no reply, such as FORGET.
+    fn send_noreply(
+        &mut self,
+        opcode: FuseOpcode,
+        nodeid: u64,
+        payload: &[u8],
+    ) -> std::io::Result<()> {
+        if self.destroyed {
+            return Err(FuseError::new(libc::ENOTCONN).into_io());
+        }
+
+        let unique = self.alloc_unique();
+        let header = FuseInHeader::new(opcode as u32, nodeid, unique, payload.len());
+
+        let mut msg = bytemuck::bytes_of(&header).to_vec();
+        msg.extend_from_slice(payload);
+
+        self.stream.send(&msg)
+    }
+
+    /// INIT asking for DEFAULT_INIT_FLAGS.
+    pub fn send_init(&mut self) -> std::io::Result<SessionInfo> {
+        self.send_init_with(DEFAULT_INIT_FLAGS)
+    }
+
+    /// INIT asking for the capabilities in `flags`. The result is also
+    /// kept, see `session`.
+    pub fn send_init_with(&mut self, flags: FuseInitFlags) -> std::io::Result<SessionInfo> {
+        let init_in = FuseInitIn::new(flags);
         let payload = bytemuck::bytes_of(&init_in);
 
-        let (hdr, payload_bytes) = self.send_request(FUSE_INIT, 0, payload)?;
+        let (hdr, payload_bytes) = self.send_request(FuseOpcode::Init, 0, payload)?;
 
         if hdr.error != 0 {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                format!("FUSE_INIT error {}", hdr.error),
-            ));
+            return Err(std::io::Error::other(format!(
+                "FUSE_INIT error {}",
+                hdr.error
+            )));
         }
 
         // Parse the output
         let init_out = FuseInitOut::parse(&payload_bytes)?;
 
+        self.session = SessionInfo::negotiate(flags | FuseInitFlags::INIT_EXT, &init_out);
+
         println!(
-            "FUSE INIT OK: daemon supports major={} minor={} max_write={} flags={:#x}",
-            init_out.major, init_out.minor, init_out.max_write, init_out.flags
+            "FUSE INIT OK: daemon supports major={} minor={} max_write={} flags={:?}",
+            self.session.major, self.session.minor, self.session.max_write, self.session.flags
         );
 
-        Ok(init_out)
+        Ok(self.session)
     }
 
     pub fn lookup(&mut self, parent: u64, name: &str) -> std::io::Result<FuseEntryOut> {
@@ -116,13 +561,13 @@ impl<T: FuseTransport> FuseProtocol<T> {
         payload.push(0);
 
         // Send the request
-        let (hdr, resp_payload) = self.send_request(FUSE_LOOKUP, parent, &payload)?;
+        let (hdr, resp_payload) = self.send_request(FuseOpcode::Lookup, parent, &payload)?;
 
         if hdr.error != 0 {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                format!("LOOKUP failed with error {}", hdr.error),
-            ));
+            return Err(std::io::Error::other(format!(
+                "LOOKUP failed with error {}",
+                hdr.error
+            )));
         }
 
         // Parse fuse_entry_out from response payload
@@ -131,29 +576,125 @@ impl<T: FuseTransport> FuseProtocol<T> {
         Ok(entry)
     }
 
+    /// Drops `nlookup` of the references LOOKUP (and CREATE, MKNOD, ...)
+    /// took on `nodeid`. The server does not reply.
+    pub fn forget(&mut self, nodeid: u64, nlookup: u64) -> std::io::Result<()> {
+        let input = FuseForgetIn { nlookup };
+        self.send_noreply(FuseOpcode::Forget, nodeid, bytemuck::bytes_of(&input))
+    }
+
+    /// FORGET for many `(nodeid, nlookup)` pairs at once. Servers older than
+    /// 7.16 get one FORGET each. FORGET is only advice, so on a transport
+    /// that cannot send without a reply nothing is sent and the server keeps
+    /// the references until it is unmounted.
+    pub fn batch_forget(&mut self, nodes: &[(u64, u64)]) -> std::io::Result<()> {
+        match self.send_forgets(nodes) {
+            Err(e) if e.kind() == std::io::ErrorKind::Unsupported => Ok(()),
+            res => res,
+        }
+    }
+
+    fn send_forgets(&mut self, nodes: &[(u64, u64)]) -> std::io::Result<()> {
+        if self.session.minor < 16 {
+            for &(nodeid, nlookup) in nodes {
+                self.forget(nodeid, nlookup)?;
+            }
+            return Ok(());
+        }
+
+        for chunk in nodes.chunks(FORGET_BATCH) {
+            let input = FuseBatchForgetIn {
+                count: chunk.len() as u32,
+                dummy: 0,
+            };
+            let mut payload = bytemuck::bytes_of(&input).to_vec();
+            for &(nodeid, nlookup) in chunk {
+                payload.extend_from_slice(bytemuck::bytes_of(&FuseForgetOne { nodeid, nlookup }));
+            }
+
+            self.send_noreply(FuseOpcode::BatchForget, 0, &payload)?;
+        }
+
+        Ok(())
+    }
+
     pub fn open(&mut self, nodeid: u64, flags: u32) -> std::io::Result<FuseOpenOut> {
         let input = FuseOpenIn::new(flags);
         let payload = bytemuck::bytes_of(&input);
 
-        let (hdr, resp_payload) = self.send_request(FUSE_OPEN, nodeid, payload)?;
+        let (hdr, resp_payload) = self.send_request(FuseOpcode::Open, nodeid, payload)?;
 
         if hdr.error != 0 {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                format!("OPEN failed: {}", hdr.error),
-            ));
+            return Err(std::io::Error::other(format!("OPEN failed: {}", hdr.error)));
         }
 
         let out = FuseOpenOut::parse(&resp_payload)?;
         Ok(out)
     }
 
+    /// Atomically creates and opens `name` in `parent`.
+    /// The reply is a fuse_entry_out immediately followed by a fuse_open_out.
+    pub fn create(
+        &mut self,
+        parent: u64,
+        name: &str,
+        flags: u32,
+        mode: u32,
+        umask: u32,
+    ) -> std::io::Result<(FuseEntryOut, FuseOpenOut)> {
+        let input = FuseCreateIn::new(flags, mode, umask);
+        let mut payload = bytemuck::bytes_of(&input).to_vec();
+
+        // payload = create_in | name\0
+        payload.extend_from_slice(name.as_bytes());
+        payload.push(0);
+
+        let (hdr, resp) = self.send_request(FuseOpcode::Create, parent, &payload)?;
+
+        if hdr.error != 0 {
+            return Err(std::io::Error::other(format!(
+                "CREATE failed with error {}",
+                hdr.error
+            )));
+        }
+
+        let entry = FuseEntryOut::parse(&resp)?;
+        let open = FuseOpenOut::parse(&resp[std::mem::size_of::<FuseEntryOut>()..])?;
+        Ok((entry, open))
+    }
+
+    /// Reads up to `size` bytes at `offset`, in READs of at most `max_read`
+    /// bytes. Stops at the first short one, so less comes back at EOF.
     pub fn read(
         &mut self,
         nodeid: u64,
         fh: u64,
         offset: u64,
         size: u32,
+    ) -> std::io::Result<Vec<u8>> {
+        let max_read = self.max_read().max(1);
+        let mut data = Vec::new();
+
+        while data.len() < size as usize {
+            let chunk = (size - data.len() as u32).min(max_read);
+            let got = self.read_chunk(nodeid, fh, offset + data.len() as u64, chunk)?;
+            let short = got.len() < chunk as usize;
+            data.extend(got);
+
+            if short {
+                break;
+            }
+        }
+
+        Ok(data)
+    }
+
+    fn read_chunk(
+        &mut self,
+        nodeid: u64,
+        fh: u64,
+        offset: u64,
+        size: u32,
     ) -> std::io::Result<Vec<u8>> {
         let req = FuseReadIn {
             fh,
@@ -167,156 +708,1490 @@ impl<T: FuseTransport> FuseProtocol<T> {
 
         let payload = bytemuck::bytes_of(&req);
 
-        let (hdr, data) = self.send_request(FUSE_READ, nodeid, payload)?;
+        let (hdr, data) = self.send_request(FuseOpcode::Read, nodeid, payload)?;
 
         if hdr.error != 0 {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                format!("READ failed with error {}", hdr.error),
-            ));
+            return Err(std::io::Error::other(format!(
+                "READ failed with error {}",
+                hdr.error
+            )));
         }
 
         Ok(data)
     }
 
-    pub fn release(&mut self, inode: u64, fh: u64) -> std::io::Result<()> {
-        // Build fuse_release_in
-        let release_in = FuseReleaseIn {
-            fh,
-            flags: 0,
-            release_flags: 0,
-            lock_owner: 0,
-        };
+    /// Writes `data` at `offset`, splitting it into chunks of at most
+    /// `max_write` bytes. Returns the number of bytes the server accepted,
+    /// which is short if the server stops early.
+    pub fn write(
+        &mut self,
+        nodeid: u64,
+        fh: u64,
+        offset: u64,
+        data: &[u8],
+    ) -> std::io::Result<usize> {
+        let mut written = 0usize;
 
-        // SAFELY :) reinterpret struct as bytes
-        let bytes = bytemuck::bytes_of(&release_in);
+        for chunk in data.chunks(self.session.max_write as usize) {
+            let req = FuseWriteIn {
+                fh,
+                offset: offset + written as u64,
+                size: chunk.len() as u32,
+                write_flags: 0,
+                lock_owner: 0,
+                flags: 0,
+                padding: 0,
+            };
 
-        // Send request — reply has no payload
-        let (hdr, _) = self.send_request(FUSE_RELEASE, inode, bytes)?;
+            // payload = fuse_write_in | data
+            let mut payload = bytemuck::bytes_of(&req).to_vec();
+            payload.extend_from_slice(chunk);
 
-        if hdr.error != 0 {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                format!("RELEASE failed with error {}", hdr.error),
-            ));
+            let (hdr, resp) = self.send_request(FuseOpcode::Write, nodeid, &payload)?;
+
+            if hdr.error != 0 {
+                return Err(std::io::Error::other(format!(
+                    "WRITE failed with error {}",
+                    hdr.error
+                )));
+            }
+
+            let out = FuseWriteOut::parse(&resp)?;
+            written += out.size as usize;
+
+            if (out.size as usize) < chunk.len() {
+                break;
+            }
         }
 
-        Ok(())
+        Ok(written)
     }
 
-    pub fn getattr(&mut self, nodeid: u64) -> std::io::Result<FuseAttrOut> {
-        let inmsg = FuseGetattrIn::new();
-        let payload = bytemuck::bytes_of(&inmsg);
+    /// Copies up to `input.len` bytes from `nodeid_in` to `input.nodeid_out`
+    /// on the server. Returns how many bytes were copied, which may be short.
+    pub fn copy_file_range(
+        &mut self,
+        nodeid_in: u64,
+        input: &FuseCopyFileRangeIn,
+    ) -> std::io::Result<usize> {
+        let payload = bytemuck::bytes_of(input);
 
-        let (hdr, resp) = self.send_request(FUSE_GETATTR, nodeid, payload)?;
+        let (hdr, resp) = self.send_request(FuseOpcode::CopyFileRange, nodeid_in, payload)?;
 
         if hdr.error != 0 {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                format!("GETATTR failed with error {}", hdr.error),
-            ));
+            return Err(std::io::Error::other(format!(
+                "COPY_FILE_RANGE failed with error {}",
+                hdr.error
+            )));
         }
 
-        let out = FuseAttrOut::parse(&resp)?;
-        Ok(out)
+        // The reply is a fuse_write_out.
+        Ok(FuseWriteOut::parse(&resp)?.size as usize)
     }
 
-    pub fn readdir(
+    /// SEEK_DATA / SEEK_HOLE: the next data or hole offset at or after
+    /// `offset`. ENXIO means there is none before EOF.
+    pub fn lseek(
         &mut self,
         nodeid: u64,
         fh: u64,
         offset: u64,
-        size: u32,
-    ) -> std::io::Result<Vec<DirEntry>> {
-        let req = FuseReadIn {
+        whence: u32,
+    ) -> std::io::Result<u64> {
+        let input = FuseLseekIn {
             fh,
             offset,
-            size,
-            read_flags: 0,
-            lock_owner: 0,
-            flags: 0,
+            whence,
             padding: 0,
         };
+        let payload = bytemuck::bytes_of(&input);
 
-        let payload = bytemuck::bytes_of(&req);
-
-        let (hdr, data) = self.send_request(FUSE_READDIR, nodeid, payload)?;
+        let (hdr, resp) = self.send_request(FuseOpcode::Lseek, nodeid, payload)?;
 
         if hdr.error != 0 {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                format!("READDIR failed: {}", hdr.error),
-            ));
-        }
-
-        if data.is_empty() {
-            return Ok(Vec::new());
+            return Err(std::io::Error::other(format!(
+                "LSEEK failed with error {}",
+                hdr.error
+            )));
         }
 
-        DirEntry::parse_dirents(&data)
+        Ok(FuseLseekOut::parse(&resp)?.offset)
     }
 
-    pub fn mkdir(&mut self, parent: u64, name: &str, mode: u32) -> std::io::Result<FuseEntryOut> {
-        // build mkdir_inn
-        let mk = FuseMkdirIn::new(mode, 0);
-        let mut payload = bytemuck::bytes_of(&mk).to_vec();
-
-        //  build payload = mk | name\0
-        payload.extend_from_slice(name.as_bytes());
-        payload.push(0);
+    /// Sent on every close of a file handle, before RELEASE. Servers report
+    /// deferred write errors here and drop POSIX locks held by `lock_owner`.
+    pub fn flush(&mut self, nodeid: u64, fh: u64, lock_owner: u64) -> std::io::Result<()> {
+        let input = FuseFlushIn {
+            fh,
+            unused: 0,
+            padding: 0,
+            lock_owner,
+        };
+        let payload = bytemuck::bytes_of(&input);
 
-        // Step 3: send
-        let (hdr, resp) = self.send_request(FUSE_MKDIR, parent, &payload)?;
+        let (hdr, _) = self.send_request(FuseOpcode::Flush, nodeid, payload)?;
 
         if hdr.error != 0 {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                format!("MKDIR failed with error {}", hdr.error),
-            ));
+            return Err(std::io::Error::other(format!(
+                "FLUSH failed with error {}",
+                hdr.error
+            )));
         }
 
-        // Step 4: parse entry
-        let entry = FuseEntryOut::parse(&resp)?;
-        Ok(entry)
+        Ok(())
     }
 
-    pub fn releasedir(&mut self, nodeid: u64, fh: u64) -> std::io::Result<()> {
-        let input = FuseReleaseIn {
+    pub fn fsync(&mut self, nodeid: u64, fh: u64, datasync: bool) -> std::io::Result<()> {
+        self.fsync_common(FuseOpcode::Fsync, "FSYNC", nodeid, fh, datasync)
+    }
+
+    /// Allocates, punches or zeroes `length` bytes at `offset`, depending on
+    /// the FALLOC_FL_* bits in `mode` (0 = plain preallocation).
+    pub fn fallocate(
+        &mut self,
+        nodeid: u64,
+        fh: u64,
+        offset: u64,
+        length: u64,
+        mode: u32,
+    ) -> std::io::Result<()> {
+        let input = FuseFallocateIn {
             fh,
-            flags: 0,
-            release_flags: 0,
-            lock_owner: 0,
+            offset,
+            length,
+            mode,
+            padding: 0,
         };
-
         let payload = bytemuck::bytes_of(&input);
 
-        let (hdr, _) = self.send_request(FUSE_RELEASEDIR, nodeid, payload)?;
+        let (hdr, _) = self.send_request(FuseOpcode::Fallocate, nodeid, payload)?;
 
         if hdr.error != 0 {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                format!("RELEASEDIR failed: {}", hdr.error),
-            ));
+            return Err(std::io::Error::other(format!(
+                "FALLOCATE failed with error {}",
+                hdr.error
+            )));
         }
 
         Ok(())
     }
 
-    pub fn opendir(&mut self, nodeid: u64) -> std::io::Result<FuseOpenOut> {
-        // OPENDIR uses FuseOpenIn exactly like OPEN
-        let input = FuseOpenIn::new(libc::O_RDONLY as u32);
-        let payload = bytemuck::bytes_of(&input);
-
-        let (hdr, resp_payload) = self.send_request(FUSE_OPENDIR, nodeid, payload)?;
+    /// Sends FUSE_IOCTL `cmd`. `arg` is the memory the ioctl argument points
+    /// at; the server sees its address in `fuse_ioctl_in.arg`.
+    ///
+    /// Without FUSE_IOCTL_UNRESTRICTED in `flags` the transfer is decoded
+    /// from `cmd` like the kernel does: `_IOC_SIZE` bytes of `arg` go to the
+    /// server for `_IOW`, come back for `_IOR`, or both. Unrestricted ioctls
+    /// start with no data and the server names the ranges of `arg` it wants
+    /// to read and write by replying with FUSE_IOCTL_RETRY and iovecs.
+    ///
+    /// Returns the ioctl's return value; `arg` holds whatever the server
+    /// wrote back.
+    pub fn ioctl(
+        &mut self,
+        nodeid: u64,
+        fh: u64,
+        flags: u32,
+        cmd: u32,
+        arg: &mut [u8],
+    ) -> std::io::Result<i32> {
+        let base = arg.as_ptr() as u64;
+        let mut in_iovs = Vec::new();
+        let mut out_iovs = Vec::new();
 
-        if hdr.error != 0 {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                format!("OPENDIR failed: {}", hdr.error),
-            ));
+        if flags & FUSE_IOCTL_UNRESTRICTED == 0 {
+            let iov = FuseIoctlIovec {
+                base,
+                len: ioc_size(cmd) as u64,
+            };
+            if ioc_dir(cmd) & IOC_WRITE != 0 {
+                in_iovs.push(iov);
+            }
+            if ioc_dir(cmd) & IOC_READ != 0 {
+                out_iovs.push(iov);
+            }
         }
 
-        let out = FuseOpenOut::parse(&resp_payload)?;
-        Ok(out)
+        for _ in 0..=IOCTL_RETRIES {
+            let in_size = iovs_len(&in_iovs)?;
+            let out_size = iovs_len(&out_iovs)?;
+
+            let input = FuseIoctlIn {
+                fh,
+                flags,
+                cmd,
+                arg: base,
+                in_size,
+                out_size,
+            };
+            let mut payload = bytemuck::bytes_of(&input).to_vec();
+            for iov in &in_iovs {
+                payload.extend_from_slice(&arg[iov_range(iov, base, arg.len())?]);
+            }
+
+            let (hdr, resp) = self.send_request(FuseOpcode::Ioctl, nodeid, &payload)?;
+
+            if hdr.error != 0 {
+                return Err(std::io::Error::other(format!(
+                    "IOCTL failed with error {}",
+                    hdr.error
+                )));
+            }
+
+            let out = FuseIoctlOut::parse(&resp)?;
+            let data = &resp[std::mem::size_of::<FuseIoctlOut>()..];
+
+            if out.flags & FUSE_IOCTL_RETRY == 0 {
+                if data.len() > out_size as usize {
+                    return Err(FuseError::new(libc::EIO).into_io());
+                }
+
+                // Scatter the reply over the out iovecs in order.
+                let mut rest = data;
+                for iov in &out_iovs {
+                    let range = iov_range(iov, base, arg.len())?;
+                    let n = rest.len().min(range.len());
+                    arg[range.start..range.start + n].copy_from_slice(&rest[..n]);
+                    rest = &rest[n..];
+                }
+
+                return Ok(out.result);
+            }
+
+            // The kernel only honours RETRY for unrestricted ioctls.
+            if flags & FUSE_IOCTL_UNRESTRICTED == 0 {
+                return Err(FuseError::new(libc::EIO).into_io());
+            }
+
+            let n_in = out.in_iovs as usize;
+            let n_out = out.out_iovs as usize;
+            let iov_size = std::mem::size_of::<FuseIoctlIovec>();
+
+            if n_in + n_out > FUSE_IOCTL_MAX_IOV || data.len() != (n_in + n_out) * iov_size {
+                return Err(FuseError::new(libc::EIO).into_io());
+            }
+
+            let mut iovs: Vec<FuseIoctlIovec> = data
+                .chunks_exact(iov_size)
+                .map(bytemuck::pod_read_unaligned)
+                .collect();
+            out_iovs = iovs.split_off(n_in);
+            in_iovs = iovs;
+        }
+
+        // The server keeps asking for different buffers.
+        Err(FuseError::new(libc::EIO).into_io())
+    }
+
+    /// Current readiness of the file for `events` (POLLIN etc.). With
+    /// FUSE_POLL_SCHEDULE_NOTIFY in `flags` the server also sends
+    /// FUSE_NOTIFY_POLL for `kh` once that changes; see `wait_poll_wakeup`.
+    pub fn poll(
+        &mut self,
+        nodeid: u64,
+        fh: u64,
+        kh: u64,
+        flags: u32,
+        events: u32,
+    ) -> std::io::Result<u32> {
+        // Any earlier wakeup predates this answer.
+        self.poll_wakeups.remove(&kh);
+
+        let input = FusePollIn {
+            fh,
+            kh,
+            flags,
+            events,
+        };
+        let payload = bytemuck::bytes_of(&input);
+
+        let (hdr, resp) = self.send_request(FuseOpcode::Poll, nodeid, payload)?;
+
+        if hdr.error != 0 {
+            return Err(std::io::Error::other(format!(
+                "POLL failed with error {}",
+                hdr.error
+            )));
+        }
+
+        let revents = FusePollOut::parse(&resp)?.revents;
+        if revents != 0 {
+            self.poll_backoff.remove(&kh);
+        }
+        Ok(revents)
+    }
+
+    /// Forgets what is known about poll handle `kh`, once its file is
+    /// closed.
+    pub fn release_poll_handle(&mut self, kh: u64) {
+        self.poll_wakeups.remove(&kh);
+        self.poll_backoff.remove(&kh);
+    }
+
+    /// Blocks until the server sends FUSE_NOTIFY_POLL for `kh` or `timeout`
+    /// passes, whichever is first; poll again afterwards either way. Fails
+    /// with EINTR when the cancel flag is set.
+    ///
+    /// Transports that cannot receive notifications just sleep, starting at
+    /// POLL_FALLBACK and backing off for as long as `kh` stays idle.
+    pub fn wait_poll_wakeup(&mut self, kh: u64, timeout: Option<Duration>) -> std::io::Result<()> {
+        if !self.stream.supports_recv() {
+            let backoff = self.poll_backoff.entry(kh).or_insert(POLL_FALLBACK);
+            let wait = timeout.map_or(*backoff, |t| t.min(*backoff));
+            *backoff = (*backoff * 2).min(POLL_FALLBACK_MAX);
+
+            std::thread::sleep(wait);
+            if self.take_cancel() {
+                return Err(FuseError::new(libc::EINTR).into_io());
+            }
+            return Ok(());
+        }
+
+        let deadline = timeout.map(|t| Instant::now() + t);
+
+        loop {
+            if self.poll_wakeups.remove(&kh) {
+                return Ok(());
+            }
+            if self.take_cancel() {
+                return Err(FuseError::new(libc::EINTR).into_io());
+            }
+
+            let now = Instant::now();
+            if deadline.is_some_and(|d| now >= d) {
+                return Ok(());
+            }
+
+            let wait = deadline.map_or(CANCEL_POLL, |d| (d - now).min(CANCEL_POLL));
+            if let Some(raw) = self.stream.recv(Some(wait))? {
+                self.unsolicited(&raw)?;
+            }
+        }
+    }
+
+    /// `fh` is a directory handle from OPENDIR.
+    pub fn fsyncdir(&mut self, nodeid: u64, fh: u64, datasync: bool) -> std::io::Result<()> {
+        self.fsync_common(FuseOpcode::Fsyncdir, "FSYNCDIR", nodeid, fh, datasync)
+    }
+
+    /// Writes back everything the server holds for the filesystem `nodeid`
+    /// lives on (syncfs(2)); the kernel sends it for the root.
+    pub fn syncfs(&mut self, nodeid: u64) -> std::io::Result<()> {
+        let input = FuseSyncfsIn { padding: 0 };
+        let payload = bytemuck::bytes_of(&input);
+
+        let (hdr, _) = self.send_request(FuseOpcode::Syncfs, nodeid, payload)?;
+
+        if hdr.error != 0 {
+            return Err(std::io::Error::other(format!(
+                "SYNCFS failed with error {}",
+                hdr.error
+            )));
+        }
+
+        Ok(())
+    }
+
+    fn fsync_common(
+        &mut self,
+        opcode: FuseOpcode,
+        name: &str,
+        nodeid: u64,
+        fh: u64,
+        datasync: bool,
+    ) -> std::io::Result<()> {
+        let input = FuseFsyncIn {
+            fh,
+            fsync_flags: if datasync { FUSE_FSYNC_FDATASYNC } else { 0 },
+            padding: 0,
+        };
+        let payload = bytemuck::bytes_of(&input);
+
+        let (hdr, _) = self.send_request(opcode, nodeid, payload)?;
+
+        if hdr.error != 0 {
+            return Err(std::io::Error::other(format!(
+                "{} failed with error {}",
+                name, hdr.error
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// `release_flags` may carry FUSE_RELEASE_FLOCK_UNLOCK, in which case
+    /// `lock_owner` names the flock() holder to unlock.
+    pub fn release(
+        &mut self,
+        inode: u64,
+        fh: u64,
+        release_flags: u32,
+        lock_owner: u64,
+    ) -> std::io::Result<()> {
+        // Build fuse_release_in
+        let release_in = FuseReleaseIn {
+            fh,
+            flags: 0,
+            release_flags,
+            lock_owner,
+        };
+
+        // SAFELY :) reinterpret struct as bytes
+        let bytes = bytemuck::bytes_of(&release_in);
+
+        // Send request — reply has no payload
+        let (hdr, _) = self.send_request(FuseOpcode::Release, inode, bytes)?;
+
+        if hdr.error != 0 {
+            return Err(std::io::Error::other(format!(
+                "RELEASE failed with error {}",
+                hdr.error
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Returns the first lock conflicting with `lk`, or `lk` with typ F_UNLCK
+    /// if it could be placed.
+    pub fn getlk(
+        &mut self,
+        nodeid: u64,
+        fh: u64,
+        owner: u64,
+        lk: FuseFileLock,
+    ) -> std::io::Result<FuseFileLock> {
+        let resp = self.lk_request(FuseOpcode::Getlk, nodeid, fh, owner, lk, 0)?;
+        Ok(FuseLkOut::parse(&resp)?.lk)
+    }
+
+    /// Places or (typ F_UNLCK) removes a lock; fails with EAGAIN on conflict.
+    /// `flock` selects flock(2) semantics (FUSE_LK_FLOCK) over POSIX locks.
+    pub fn setlk(
+        &mut self,
+        nodeid: u64,
+        fh: u64,
+        owner: u64,
+        lk: FuseFileLock,
+        flock: bool,
+    ) -> std::io::Result<()> {
+        let flags = if flock { FUSE_LK_FLOCK } else { 0 };
+        self.lk_request(FuseOpcode::Setlk, nodeid, fh, owner, lk, flags)?;
+        Ok(())
+    }
+
+    /// Like `setlk`, but the server holds the reply until the lock is granted.
+    pub fn setlkw(
+        &mut self,
+        nodeid: u64,
+        fh: u64,
+        owner: u64,
+        lk: FuseFileLock,
+        flock: bool,
+    ) -> std::io::Result<()> {
+        let flags = if flock { FUSE_LK_FLOCK } else { 0 };
+        self.lk_request(FuseOpcode::Setlkw, nodeid, fh, owner, lk, flags)?;
+        Ok(())
+    }
+
+    fn lk_request(
+        &mut self,
+        opcode: FuseOpcode,
+        nodeid: u64,
+        fh: u64,
+        owner: u64,
+        lk: FuseFileLock,
+        lk_flags: u32,
+    ) -> std::io::Result<Vec<u8>> {
+        let input = FuseLkIn {
+            fh,
+            owner,
+            lk,
+            lk_flags,
+            padding: 0,
+        };
+        let payload = bytemuck::bytes_of(&input);
+
+        // SETLKW blocks for as long as the lock is held elsewhere.
+        let timeout = match opcode {
+            FuseOpcode::Setlkw => None,
+            _ => self.timeout,
+        };
+        let (hdr, resp) = self.send_request_timeout(opcode, nodeid, payload, timeout)?;
+
+        if hdr.error != 0 {
+            return Err(std::io::Error::other(format!(
+                "lock request failed with error {}",
+                hdr.error
+            )));
+        }
+
+        Ok(resp)
+    }
+
+    /// Asks the server whether the request credentials may access `nodeid`
+    /// with `mask`. ENOSYS means the server leaves permission checks to us.
+    pub fn access(&mut self, nodeid: u64, mask: u32) -> std::io::Result<()> {
+        let input = FuseAccessIn { mask, padding: 0 };
+        let payload = bytemuck::bytes_of(&input);
+
+        let (hdr, _) = self.send_request(FuseOpcode::Access, nodeid, payload)?;
+
+        if hdr.error != 0 {
+            return Err(std::io::Error::other(format!(
+                "ACCESS failed with error {}",
+                hdr.error
+            )));
+        }
+
+        Ok(())
+    }
+
+    pub fn getattr(&mut self, nodeid: u64) -> std::io::Result<FuseAttrOut> {
+        let inmsg = FuseGetattrIn::new();
+        let payload = bytemuck::bytes_of(&inmsg);
+
+        let (hdr, resp) = self.send_request(FuseOpcode::Getattr, nodeid, payload)?;
+
+        if hdr.error != 0 {
+            return Err(std::io::Error::other(format!(
+                "GETATTR failed with error {}",
+                hdr.error
+            )));
+        }
+
+        let out = FuseAttrOut::parse(&resp)?;
+        Ok(out)
+    }
+
+    /// Changes the attributes selected by `attr.valid` and returns the new ones.
+    pub fn setattr(&mut self, nodeid: u64, attr: &FuseSetattrIn) -> std::io::Result<FuseAttrOut> {
+        let payload = bytemuck::bytes_of(attr);
+
+        let (hdr, resp) = self.send_request(FuseOpcode::Setattr, nodeid, payload)?;
+
+        if hdr.error != 0 {
+            return Err(std::io::Error::other(format!(
+                "SETATTR failed with error {}",
+                hdr.error
+            )));
+        }
+
+        let out = FuseAttrOut::parse(&resp)?;
+        Ok(out)
+    }
+
+    pub fn statfs(&mut self, nodeid: u64) -> std::io::Result<FuseStatfsOut> {
+        // STATFS has no request body
+        let (hdr, resp) = self.send_request(FuseOpcode::Statfs, nodeid, &[])?;
+
+        if hdr.error != 0 {
+            return Err(std::io::Error::other(format!(
+                "STATFS failed with error {}",
+                hdr.error
+            )));
+        }
+
+        let out = FuseStatfsOut::parse(&resp)?;
+        Ok(out)
+    }
+
+    /// XATTR_CREATE / XATTR_REPLACE in `flags` make an existing / missing name an error.
+    pub fn setxattr(
+        &mut self,
+        nodeid: u64,
+        name: &str,
+        value: &[u8],
+        flags: u32,
+    ) -> std::io::Result<()> {
+        let input = FuseSetxattrIn {
+            size: value.len() as u32,
+            flags,
+        };
+        let mut payload = bytemuck::bytes_of(&input).to_vec();
+
+        // payload = setxattr_in | name\0 | value
+        payload.extend_from_slice(name.as_bytes());
+        payload.push(0);
+        payload.extend_from_slice(value);
+
+        let (hdr, _) = self.send_request(FuseOpcode::Setxattr, nodeid, &payload)?;
+
+        if hdr.error != 0 {
+            return Err(std::io::Error::other(format!(
+                "SETXATTR failed with error {}",
+                hdr.error
+            )));
+        }
+
+        Ok(())
+    }
+
+    pub fn getxattr(&mut self, nodeid: u64, name: &str) -> std::io::Result<Vec<u8>> {
+        self.xattr_fetch(FuseOpcode::Getxattr, nodeid, Some(name))
+    }
+
+    /// Attribute names on `nodeid`; the reply is a list of NUL-terminated names.
+    pub fn listxattr(&mut self, nodeid: u64) -> std::io::Result<Vec<String>> {
+        let data = self.xattr_fetch(FuseOpcode::Listxattr, nodeid, None)?;
+
+        Ok(data
+            .split(|&b| b == 0)
+            .filter(|n| !n.is_empty())
+            .map(|n| String::from_utf8_lossy(n).to_string())
+            .collect())
+    }
+
+    pub fn removexattr(&mut self, nodeid: u64, name: &str) -> std::io::Result<()> {
+        let mut payload = name.as_bytes().to_vec();
+        payload.push(0);
+
+        let (hdr, _) = self.send_request(FuseOpcode::Removexattr, nodeid, &payload)?;
+
+        if hdr.error != 0 {
+            return Err(std::io::Error::other(format!(
+                "REMOVEXATTR failed with error {}",
+                hdr.error
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Two-step GETXATTR/LISTXATTR: a size 0 request returns fuse_getxattr_out
+    /// with the length needed, then a request of that size returns the data.
+    /// ERANGE on the second step means the value grew in between, so re-probe.
+    fn xattr_fetch(
+        &mut self,
+        opcode: FuseOpcode,
+        nodeid: u64,
+        name: Option<&str>,
+    ) -> std::io::Result<Vec<u8>> {
+        for _ in 0..XATTR_RETRIES {
+            let probe = self.xattr_request(opcode, nodeid, name, 0)?;
+            let needed = FuseGetxattrOut::parse(&probe)?.size;
+
+            if needed == 0 {
+                return Ok(Vec::new());
+            }
+
+            match self.xattr_request(opcode, nodeid, name, needed) {
+                Err(e) if errno_of(&e) == Some(libc::ERANGE) => continue,
+                res => return res,
+            }
+        }
+
+        Err(FuseError::new(libc::ERANGE).into_io())
+    }
+
+    fn xattr_request(
+        &mut self,
+        opcode: FuseOpcode,
+        nodeid: u64,
+        name: Option<&str>,
+        size: u32,
+    ) -> std::io::Result<Vec<u8>> {
+        let input = FuseGetxattrIn { size, padding: 0 };
+        let mut payload = bytemuck::bytes_of(&input).to_vec();
+
+        // GETXATTR: getxattr_in | name\0; LISTXATTR: getxattr_in only
+        if let Some(name) = name {
+            payload.extend_from_slice(name.as_bytes());
+            payload.push(0);
+        }
+
+        let (hdr, resp) = self.send_request(opcode, nodeid, &payload)?;
+
+        if hdr.error != 0 {
+            return Err(std::io::Error::other(format!(
+                "xattr request failed with error {}",
+                hdr.error
+            )));
+        }
+
+        Ok(resp)
+    }
+
+    pub fn readdir(
+        &mut self,
+        nodeid: u64,
+        fh: u64,
+        offset: u64,
+        size: u32,
+    ) -> std::io::Result<Vec<DirEntry>> {
+        let req = FuseReadIn {
+            fh,
+            offset,
+            size,
+            read_flags: 0,
+            lock_owner: 0,
+            flags: 0,
+            padding: 0,
+        };
+
+        let payload = bytemuck::bytes_of(&req);
+
+        let (hdr, data) = self.send_request(FuseOpcode::Readdir, nodeid, payload)?;
+
+        if hdr.error != 0 {
+            return Err(std::io::Error::other(format!(
+                "READDIR failed: {}",
+                hdr.error
+            )));
+        }
+
+        if data.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        DirEntry::parse_dirents(&data)
+    }
+
+    /// Like `readdir`, but every entry also carries its lookup result. Each
+    /// entry with a nonzero nodeid counts as a LOOKUP on the server side.
+    pub fn readdirplus(
+        &mut self,
+        nodeid: u64,
+        fh: u64,
+        offset: u64,
+        size: u32,
+    ) -> std::io::Result<Vec<DirEntry>> {
+        let req = FuseReadIn {
+            fh,
+            offset,
+            size,
+            read_flags: 0,
+            lock_owner: 0,
+            flags: 0,
+            padding: 0,
+        };
+
+        let payload = bytemuck::bytes_of(&req);
+
+        let (hdr, data) = self.send_request(FuseOpcode::Readdirplus, nodeid, payload)?;
+
+        if hdr.error != 0 {
+            return Err(std::io::Error::other(format!(
+                "READDIRPLUS failed: {}",
+                hdr.error
+            )));
+        }
+
+        if data.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        DirEntry::parse_direntplus(&data)
+    }
+
+    pub fn mkdir(&mut self, parent: u64, name: &str, mode: u32) -> std::io::Result<FuseEntryOut> {
+        // build mkdir_inn
+        let mk = FuseMkdirIn::new(mode, 0);
+        let mut payload = bytemuck::bytes_of(&mk).to_vec();
+
+        //  build payload = mk | name\0
+        payload.extend_from_slice(name.as_bytes());
+        payload.push(0);
+
+        // Step 3: send
+        let (hdr, resp) = self.send_request(FuseOpcode::Mkdir, parent, &payload)?;
+
+        if hdr.error != 0 {
+            return Err(std::io::Error::other(format!(
+                "MKDIR failed with error {}",
+                hdr.error
+            )));
+        }
+
+        // Step 4: parse entry
+        let entry = FuseEntryOut::parse(&resp)?;
+        Ok(entry)
+    }
+
+    /// Creates `name` in `parent` as a symlink pointing at `target`.
+    pub fn symlink(
+        &mut self,
+        parent: u64,
+        name: &str,
+        target: &str,
+    ) -> std::io::Result<FuseEntryOut> {
+        // payload = name\0 | target\0
+        let mut payload = name.as_bytes().to_vec();
+        payload.push(0);
+        payload.extend_from_slice(target.as_bytes());
+        payload.push(0);
+
+        let (hdr, resp) = self.send_request(FuseOpcode::Symlink, parent, &payload)?;
+
+        if hdr.error != 0 {
+            return Err(std::io::Error::other(format!(
+                "SYMLINK failed with error {}",
+                hdr.error
+            )));
+        }
+
+        let entry = FuseEntryOut::parse(&resp)?;
+        Ok(entry)
+    }
+
+    pub fn readlink(&mut self, nodeid: u64) -> std::io::Result<String> {
+        let (hdr, resp) = self.send_request(FuseOpcode::Readlink, nodeid, &[])?;
+
+        if hdr.error != 0 {
+            return Err(std::io::Error::other(format!(
+                "READLINK failed with error {}",
+                hdr.error
+            )));
+        }
+
+        // The reply is the bare target, without a trailing NUL.
+        Ok(String::from_utf8_lossy(&resp).to_string())
+    }
+
+    /// Creates `name` in `newparent` as another name for `oldnodeid`.
+    pub fn link(
+        &mut self,
+        oldnodeid: u64,
+        newparent: u64,
+        name: &str,
+    ) -> std::io::Result<FuseEntryOut> {
+        let input = FuseLinkIn { oldnodeid };
+        let mut payload = bytemuck::bytes_of(&input).to_vec();
+
+        // payload = link_in | name\0
+        payload.extend_from_slice(name.as_bytes());
+        payload.push(0);
+
+        let (hdr, resp) = self.send_request(FuseOpcode::Link, newparent, &payload)?;
+
+        if hdr.error != 0 {
+            return Err(std::io::Error::other(format!(
+                "LINK failed with error {}",
+                hdr.error
+            )));
+        }
+
+        let entry = FuseEntryOut::parse(&resp)?;
+        Ok(entry)
+    }
+
+    pub fn unlink(&mut self, parent: u64, name: &str) -> std::io::Result<()> {
+        // UNLINK carries only name\0 and has an empty reply
+        let mut payload = name.as_bytes().to_vec();
+        payload.push(0);
+
+        let (hdr, _) = self.send_request(FuseOpcode::Unlink, parent, &payload)?;
+
+        if hdr.error != 0 {
+            return Err(std::io::Error::other(format!(
+                "UNLINK failed with error {}",
+                hdr.error
+            )));
+        }
+
+        Ok(())
+    }
+
+    pub fn rmdir(&mut self, parent: u64, name: &str) -> std::io::Result<()> {
+        let mut payload = name.as_bytes().to_vec();
+        payload.push(0);
+
+        let (hdr, _) = self.send_request(FuseOpcode::Rmdir, parent, &payload)?;
+
+        if hdr.error != 0 {
+            return Err(std::io::Error::other(format!(
+                "RMDIR failed with error {}",
+                hdr.error
+            )));
+        }
+
+        Ok(())
+    }
+
+    pub fn rename(
+        &mut self,
+        olddir: u64,
+        oldname: &str,
+        newdir: u64,
+        newname: &str,
+    ) -> std::io::Result<()> {
+        let input = FuseRenameIn { newdir };
+        let mut payload = bytemuck::bytes_of(&input).to_vec();
+
+        // payload = rename_in | oldname\0 | newname\0
+        payload.extend_from_slice(oldname.as_bytes());
+        payload.push(0);
+        payload.extend_from_slice(newname.as_bytes());
+        payload.push(0);
+
+        let (hdr, _) = self.send_request(FuseOpcode::Rename, olddir, &payload)?;
+
+        if hdr.error != 0 {
+            return Err(std::io::Error::other(format!(
+                "RENAME failed with error {}",
+                hdr.error
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// FUSE_RENAME2: like `rename`, plus RENAME_NOREPLACE / EXCHANGE / WHITEOUT flags.
+    pub fn rename2(
+        &mut self,
+        olddir: u64,
+        oldname: &str,
+        newdir: u64,
+        newname: &str,
+        flags: u32,
+    ) -> std::io::Result<()> {
+        let input = FuseRename2In {
+            newdir,
+            flags,
+            padding: 0,
+        };
+        let mut payload = bytemuck::bytes_of(&input).to_vec();
+
+        payload.extend_from_slice(oldname.as_bytes());
+        payload.push(0);
+        payload.extend_from_slice(newname.as_bytes());
+        payload.push(0);
+
+        let (hdr, _) = self.send_request(FuseOpcode::Rename2, olddir, &payload)?;
+
+        if hdr.error != 0 {
+            return Err(std::io::Error::other(format!(
+                "RENAME2 failed with error {}",
+                hdr.error
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Creates a FIFO, socket, device node or empty regular file, as given
+    /// by the file type in `mode`. `rdev` only matters for devices.
+    pub fn mknod(
+        &mut self,
+        parent: u64,
+        name: &str,
+        mode: u32,
+        rdev: u32,
+        umask: u32,
+    ) -> std::io::Result<FuseEntryOut> {
+        let input = FuseMknodIn {
+            mode,
+            rdev,
+            umask,
+            padding: 0,
+        };
+        let mut payload = bytemuck::bytes_of(&input).to_vec();
+
+        // payload = mknod_in | name\0
+        payload.extend_from_slice(name.as_bytes());
+        payload.push(0);
+
+        let (hdr, resp) = self.send_request(FuseOpcode::Mknod, parent, &payload)?;
+
+        if hdr.error != 0 {
+            return Err(std::io::Error::other(format!(
+                "MKNOD failed with error {}",
+                hdr.error
+            )));
+        }
+
+        let entry = FuseEntryOut::parse(&resp)?;
+        Ok(entry)
+    }
+
+    /// Tells the server the session is over. Every request after it fails
+    /// with ENOTCONN, whether or not the server answered.
+    pub fn destroy(&mut self) -> std::io::Result<()> {
+        let res = self.send_request(FuseOpcode::Destroy, 0, &[]);
+        self.destroyed = true;
+        let (hdr, _) = res?;
+
+        if hdr.error != 0 {
+            return Err(std::io::Error::other(format!(
+                "DESTROY failed with error {}",
+                hdr.error
+            )));
+        }
+
+        Ok(())
+    }
+
+    pub fn releasedir(&mut self, nodeid: u64, fh: u64) -> std::io::Result<()> {
+        let input = FuseReleaseIn {
+            fh,
+            flags: 0,
+            release_flags: 0,
+            lock_owner: 0,
+        };
+
+        let payload = bytemuck::bytes_of(&input);
+
+        let (hdr, _) = self.send_request(FuseOpcode::Releasedir, nodeid, payload)?;
+
+        if hdr.error != 0 {
+            return Err(std::io::Error::other(format!(
+                "RELEASEDIR failed: {}",
+                hdr.error
+            )));
+        }
+
+        Ok(())
+    }
+
+    pub fn opendir(&mut self, nodeid: u64) -> std::io::Result<FuseOpenOut> {
+        // OPENDIR uses FuseOpenIn exactly like OPEN
+        let input = FuseOpenIn::new(libc::O_RDONLY as u32);
+        let payload = bytemuck::bytes_of(&input);
+
+        let (hdr, resp_payload) = self.send_request(FuseOpcode::Opendir, nodeid, payload)?;
+
+        if hdr.error != 0 {
+            return Err(std::io::Error::other(format!(
+                "OPENDIR failed: {}",
+                hdr.error
+            )));
+        }
+
+        let out = FuseOpenOut::parse(&resp_payload)?;
+        Ok(out)
+    }
+}
+
+/// `_IOC_SIZE`: size of the argument encoded in an ioctl number.
+pub(crate) fn ioc_size(cmd: u32) -> usize {
+    ((cmd >> IOC_SIZESHIFT) & IOC_SIZEMASK) as usize
+}
+
+/// `_IOC_DIR`: IOC_WRITE if the caller passes data in, IOC_READ if it
+/// expects data back.
+pub(crate) fn ioc_dir(cmd: u32) -> u32 {
+    cmd >> IOC_DIRSHIFT
+}
+
+/// Total length of an iovec list as carried in in_size / out_size.
+fn iovs_len(iovs: &[FuseIoctlIovec]) -> std::io::Result<u32> {
+    iovs.iter()
+        .try_fold(0u64, |sum, iov| sum.checked_add(iov.len))
+        .and_then(|sum| u32::try_from(sum).ok())
+        .ok_or_else(|| FuseError::new(libc::EINVAL).into_io())
+}
+
+/// Where an iovec lands in the argument buffer that starts at address
+/// `base`. Anything outside it is EFAULT, as it would be for a real
+/// process.
+fn iov_range(
+    iov: &FuseIoctlIovec,
+    base: u64,
+    len: usize,
+) -> std::io::Result<std::ops::Range<usize>> {
+    let start = iov.base.checked_sub(base);
+    let end = start.and_then(|s| s.checked_add(iov.len));
+
+    match (start, end) {
+        (Some(start), Some(end)) if end <= len as u64 => Ok(start as usize..end as usize),
+        _ => Err(FuseError::new(libc::EFAULT).into_io()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::mock::MockTransport;
+    use bytemuck::Zeroable;
+
+    /// A server speaking 7.`minor` that succeeds everything else with an
+    /// empty reply.
+    fn server(minor: u32) -> MockTransport {
+        paged_server(minor, 0)
+    }
+
+    /// `server`, offering MAX_PAGES with `max_pages` if that is not 0. READs
+    /// get as many bytes as they ask for.
+    fn paged_server(minor: u32, max_pages: u16) -> MockTransport {
+        MockTransport::new(move |req| match req.opcode {
+            op if op == FuseOpcode::Init as u32 => {
+                let mut out = FuseInitOut::zeroed();
+                out.major = FUSE_KERNEL_VERSION;
+                out.minor = minor;
+                out.max_write = 65536;
+                if max_pages != 0 {
+                    out.flags = FuseInitFlags::MAX_PAGES.bits() as u32;
+                    out.max_pages = max_pages;
+                }
+                Ok(bytemuck::bytes_of(&out).to_vec())
+            }
+            op if op == FuseOpcode::Read as u32 => {
+                let size = u32::from_le_bytes(req.body[16..20].try_into().unwrap());
+                Ok(vec![0xab; size as usize])
+            }
+            _ => Ok(Vec::new()),
+        })
+    }
+
+    fn read_sizes(sent: &std::cell::RefCell<Vec<crate::transport::mock::Request>>) -> Vec<u32> {
+        sent.borrow()
+            .iter()
+            .filter(|r| r.opcode == FuseOpcode::Read as u32)
+            .map(|r| u32::from_le_bytes(r.body[16..20].try_into().unwrap()))
+            .collect()
+    }
+
+    fn opcodes(sent: &std::cell::RefCell<Vec<crate::transport::mock::Request>>) -> Vec<u32> {
+        sent.borrow().iter().map(|r| r.opcode).collect()
+    }
+
+    /// A server with the given max_write that accepts up to `accept` bytes
+    /// of each WRITE.
+    fn write_server(max_write: u32, accept: u32) -> MockTransport {
+        MockTransport::new(move |req| match req.opcode {
+            op if op == FuseOpcode::Init as u32 => {
+                let mut out = FuseInitOut::zeroed();
+                out.major = FUSE_KERNEL_VERSION;
+                out.minor = 31;
+                out.max_write = max_write;
+                Ok(bytemuck::bytes_of(&out).to_vec())
+            }
+            op if op == FuseOpcode::Write as u32 => {
+                let size = u32::from_le_bytes(req.body[16..20].try_into().unwrap());
+                Ok([size.min(accept).to_le_bytes(), [0; 4]].concat())
+            }
+            _ => Ok(Vec::new()),
+        })
+    }
+
+    /// `(offset, size)` of every WRITE sent.
+    fn writes(sent: &std::cell::RefCell<Vec<crate::transport::mock::Request>>) -> Vec<(u64, u32)> {
+        sent.borrow()
+            .iter()
+            .filter(|r| r.opcode == FuseOpcode::Write as u32)
+            .map(|r| {
+                let offset = u64::from_le_bytes(r.body[8..16].try_into().unwrap());
+                let size = u32::from_le_bytes(r.body[16..20].try_into().unwrap());
+                (offset, size)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn writes_are_split_by_max_write() {
+        let t = write_server(4096, u32::MAX);
+        let sent = t.sent.clone();
+        let mut proto = FuseProtocol::new(t);
+        proto.send_init().unwrap();
+
+        assert_eq!(proto.write(2, 1, 100, &[7; 10000]).unwrap(), 10000);
+        assert_eq!(writes(&sent), [(100, 4096), (4196, 4096), (8292, 1808)]);
+    }
+
+    #[test]
+    fn short_write_stops_the_chunks() {
+        let t = write_server(4096, 1000);
+        let sent = t.sent.clone();
+        let mut proto = FuseProtocol::new(t);
+        proto.send_init().unwrap();
+
+        assert_eq!(proto.write(2, 1, 0, &[7; 10000]).unwrap(), 1000);
+        assert_eq!(writes(&sent), [(0, 4096)]);
+    }
+
+    /// GETXATTR sizes sent, 0 for the probes.
+    fn xattr_sizes(sent: &std::cell::RefCell<Vec<crate::transport::mock::Request>>) -> Vec<u32> {
+        sent.borrow()
+            .iter()
+            .map(|r| u32::from_le_bytes(r.body[..4].try_into().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn getxattr_probes_again_when_the_value_grew() {
+        // The value grows from 4 to 6 bytes between the first probe and fetch.
+        let mut len = 4u32;
+        let t = MockTransport::new(move |req| {
+            let size = u32::from_le_bytes(req.body[..4].try_into().unwrap());
+            if size == 0 {
+                return Ok([len.to_le_bytes(), [0; 4]].concat());
+            }
+            if len == 4 {
+                len = 6;
+            }
+            if size < len {
+                return Err(libc::ERANGE);
+            }
+            Ok(vec![b'x'; len as usize])
+        });
+        let sent = t.sent.clone();
+        let mut proto = FuseProtocol::new(t);
+
+        assert_eq!(proto.getxattr(2, "user.a").unwrap(), b"xxxxxx");
+        assert_eq!(xattr_sizes(&sent), [0, 4, 0, 6]);
+    }
+
+    #[test]
+    fn getxattr_gives_up_on_a_value_that_keeps_growing() {
+        let mut len = 0u32;
+        let t = MockTransport::new(move |req| {
+            let size = u32::from_le_bytes(req.body[..4].try_into().unwrap());
+            if size == 0 {
+                len += 1;
+                return Ok([len.to_le_bytes(), [0; 4]].concat());
+            }
+            Err(libc::ERANGE)
+        });
+        let sent = t.sent.clone();
+        let mut proto = FuseProtocol::new(t);
+
+        let err = proto.getxattr(2, "user.a").unwrap_err();
+        assert_eq!(errno_of(&err), Some(libc::ERANGE));
+        assert_eq!(sent.borrow().len(), 2 * XATTR_RETRIES);
+    }
+
+    #[test]
+    fn batch_forget_chunks_nodes() {
+        let t = server(38);
+        let sent = t.sent.clone();
+        let mut proto = FuseProtocol::new(t);
+        proto.send_init().unwrap();
+
+        let nodes: Vec<(u64, u64)> = (2..2 + FORGET_BATCH as u64 + 10).map(|n| (n, 1)).collect();
+        proto.batch_forget(&nodes).unwrap();
+
+        let forgets: Vec<_> = sent.borrow()[1..].to_vec();
+        assert_eq!(forgets.len(), 2);
+        assert!(
+            forgets
+                .iter()
+                .all(|r| r.opcode == FuseOpcode::BatchForget as u32)
+        );
+
+        let count = |r: &crate::transport::mock::Request| {
+            u32::from_le_bytes(r.body[..4].try_into().unwrap()) as usize
+        };
+        assert_eq!((count(&forgets[0]), count(&forgets[1])), (FORGET_BATCH, 10));
+    }
+
+    #[test]
+    fn batch_forget_on_old_server_sends_single_forgets() {
+        let t = server(15);
+        let sent = t.sent.clone();
+        let mut proto = FuseProtocol::new(t);
+        proto.send_init().unwrap();
+
+        proto.batch_forget(&[(2, 1), (3, 4)]).unwrap();
+
+        let forget = FuseOpcode::Forget as u32;
+        assert_eq!(opcodes(&sent)[1..], [forget, forget]);
+        assert_eq!(sent.borrow()[2].nodeid, 3);
+    }
+
+    #[test]
+    fn batch_forget_skips_transports_without_send() {
+        let t = server(38).roundtrip_only();
+        let sent = t.sent.clone();
+        let mut proto = FuseProtocol::new(t);
+        proto.send_init().unwrap();
+
+        proto.batch_forget(&[(2, 1)]).unwrap();
+        assert_eq!(opcodes(&sent), [FuseOpcode::Init as u32]);
+    }
+
+    #[test]
+    fn nothing_is_sent_after_destroy() {
+        let t = server(38);
+        let sent = t.sent.clone();
+        let mut proto = FuseProtocol::new(t);
+        proto.send_init().unwrap();
+        proto.destroy().unwrap();
+
+        let err = proto.getattr(1).unwrap_err();
+        assert_eq!(errno_of(&err), Some(libc::ENOTCONN));
+        let err = proto.forget(2, 1).unwrap_err();
+        assert_eq!(errno_of(&err), Some(libc::ENOTCONN));
+
+        let destroy = FuseOpcode::Destroy as u32;
+        assert_eq!(opcodes(&sent), [FuseOpcode::Init as u32, destroy]);
+    }
+
+    #[test]
+    fn ioc_size_and_dir_decode_the_number() {
+        // FS_IOC_GETFLAGS: _IOR('f', 1, long)
+        let getflags = (IOC_READ << IOC_DIRSHIFT) | (8 << IOC_SIZESHIFT) | (b'f' as u32) << 8 | 1;
+        assert_eq!(ioc_size(getflags), 8);
+        assert_eq!(ioc_dir(getflags), IOC_READ);
+
+        // _IOWR with the largest size the field holds.
+        let big = ((IOC_READ | IOC_WRITE) << IOC_DIRSHIFT) | (IOC_SIZEMASK << IOC_SIZESHIFT);
+        assert_eq!(ioc_size(big), IOC_SIZEMASK as usize);
+        assert_eq!(ioc_dir(big), IOC_READ | IOC_WRITE);
+
+        // _IO: no argument.
+        assert_eq!(ioc_size(0x5401), 0);
+        assert_eq!(ioc_dir(0x5401), 0);
+    }
+
+    #[test]
+    fn iov_range_maps_into_the_argument() {
+        let iov = |base, len| FuseIoctlIovec { base, len };
+
+        assert_eq!(iov_range(&iov(0x1000, 16), 0x1000, 64).unwrap(), 0..16);
+        assert_eq!(iov_range(&iov(0x1030, 16), 0x1000, 64).unwrap(), 48..64);
+        assert_eq!(iov_range(&iov(0x1040, 0), 0x1000, 64).unwrap(), 64..64);
+    }
+
+    #[test]
+    fn iov_range_outside_the_argument_is_efault() {
+        let iov = |base, len| FuseIoctlIovec { base, len };
+        let efault = |res: std::io::Result<std::ops::Range<usize>>| {
+            errno_of(&res.unwrap_err()) == Some(libc::EFAULT)
+        };
+
+        // Before the start, past the end, and overflowing the address space.
+        assert!(efault(iov_range(&iov(0xfff, 1), 0x1000, 64)));
+        assert!(efault(iov_range(&iov(0x1031, 16), 0x1000, 64)));
+        assert!(efault(iov_range(&iov(u64::MAX, 2), 0x1000, 64)));
+    }
+
+    #[test]
+    fn iovs_len_rejects_overflow() {
+        let iov = |len| FuseIoctlIovec { base: 0, len };
+
+        assert_eq!(iovs_len(&[iov(3), iov(5)]).unwrap(), 8);
+        let err = iovs_len(&[iov(u32::MAX as u64), iov(1)]).unwrap_err();
+        assert_eq!(errno_of(&err), Some(libc::EINVAL));
+        let err = iovs_len(&[iov(u64::MAX), iov(1)]).unwrap_err();
+        assert_eq!(errno_of(&err), Some(libc::EINVAL));
+    }
+
+    #[test]
+    fn released_poll_handle_leaves_nothing_behind() {
+        let t = server(38).roundtrip_only();
+        let mut proto = FuseProtocol::new(t);
+        proto.poll_wakeups.insert(5);
+
+        proto.wait_poll_wakeup(5, Some(Duration::ZERO)).unwrap();
+        assert!(proto.poll_backoff.contains_key(&5));
+
+        proto.release_poll_handle(5);
+        assert!(proto.poll_wakeups.is_empty());
+        assert!(proto.poll_backoff.is_empty());
+    }
+
+    #[test]
+    fn reads_are_split_by_max_pages() {
+        let t = paged_server(38, 4);
+        let sent = t.sent.clone();
+        let mut proto = FuseProtocol::new(t);
+        proto.send_init().unwrap();
+        assert_eq!(proto.max_read(), 4 * PAGE_SIZE);
+
+        let data = proto.read(2, 1, 0, 40000).unwrap();
+        assert_eq!(data.len(), 40000);
+        assert_eq!(read_sizes(&sent), [16384, 16384, 7232]);
+    }
+
+    #[test]
+    fn reads_fit_the_transport_reply_buffer() {
+        let t = paged_server(38, 256).max_message(64 * 1024);
+        let mut proto = FuseProtocol::new(t);
+        proto.send_init().unwrap();
+
+        assert_eq!(proto.max_read(), 64 * 1024 - 16);
+    }
+
+    #[test]
+    fn reads_without_max_pages_use_the_default() {
+        let t = paged_server(38, 0);
+        let mut proto = FuseProtocol::new(t);
+        proto.send_init().unwrap();
+
+        assert_eq!(proto.max_read(), DEFAULT_MAX_PAGES as u32 * PAGE_SIZE);
+    }
+
+    #[test]
+    fn init_flags_round_trip_through_both_words() {
+        let flags =
+            FuseInitFlags::POSIX_LOCKS | FuseInitFlags::INIT_EXT | FuseInitFlags::HAS_RESEND;
+        let (lo, hi) = flags.to_wire();
+        assert_eq!(lo, (1 << 1) | (1 << 30));
+        assert_eq!(hi, 1 << 7);
+        assert_eq!(FuseInitFlags::from_wire(lo, hi), flags);
+
+        // Bits without a name survive.
+        let unknown = FuseInitFlags::from_wire(1 << 31, 1 << 31);
+        assert_eq!(unknown.to_wire(), (1 << 31, 1 << 31));
+    }
+
+    #[test]
+    fn negotiate_keeps_only_requested_flags() {
+        let mut out = FuseInitOut::zeroed();
+        out.minor = 38;
+        let offered = FuseInitFlags::POSIX_LOCKS
+            | FuseInitFlags::ASYNC_READ
+            | FuseInitFlags::MAX_PAGES
+            | FuseInitFlags::INIT_EXT
+            | FuseInitFlags::SECURITY_CTX;
+        (out.flags, out.flags2) = offered.to_wire();
+        out.max_pages = 0;
+        out.time_gran = 1000;
+
+        let requested = FuseInitFlags::POSIX_LOCKS
+            | FuseInitFlags::MAX_PAGES
+            | FuseInitFlags::SECURITY_CTX
+            | FuseInitFlags::FLOCK_LOCKS;
+        let info = SessionInfo::negotiate(requested, &out);
+
+        assert_eq!(
+            info.flags,
+            FuseInitFlags::POSIX_LOCKS | FuseInitFlags::MAX_PAGES | FuseInitFlags::SECURITY_CTX
+        );
+        // A MAX_PAGES of 0 still allows one page.
+        assert_eq!(info.max_pages, 1);
+        assert_eq!(info.time_gran, 1000);
+        assert_eq!(info.max_write, DEFAULT_MAX_WRITE);
+    }
+
+    #[test]
+    fn negotiate_ignores_flags2_without_init_ext() {
+        let mut out = FuseInitOut::zeroed();
+        out.minor = 38;
+        out.flags = FuseInitFlags::POSIX_LOCKS.bits() as u32;
+        out.flags2 = (FuseInitFlags::SECURITY_CTX.bits() >> 32) as u32;
+        out.max_pages = 64;
+
+        let info = SessionInfo::negotiate(FuseInitFlags::all(), &out);
+        assert_eq!(info.flags, FuseInitFlags::POSIX_LOCKS);
+        assert_eq!(info.max_pages, DEFAULT_MAX_PAGES);
+    }
+
+    #[test]
+    fn init_out_parses_compat_sizes() {
+        let mut full = FuseInitOut::zeroed();
+        full.major = 7;
+        full.minor = 38;
+        full.max_readahead = 0x20000;
+        full.flags = 0x40;
+        full.max_write = 8192;
+        full.time_gran = 1;
+        full.max_pages = 64;
+        let bytes = bytemuck::bytes_of(&full);
+        assert_eq!(bytes.len(), 64);
+
+        let out = FuseInitOut::parse(&bytes[..8]).unwrap();
+        assert_eq!((out.major, out.minor), (7, 38));
+        assert_eq!((out.flags, out.max_write), (0, 0));
+
+        let out = FuseInitOut::parse(&bytes[..24]).unwrap();
+        assert_eq!((out.flags, out.max_write), (0x40, 8192));
+        assert_eq!((out.time_gran, out.max_pages), (0, 0));
+
+        let out = FuseInitOut::parse(bytes).unwrap();
+        assert_eq!((out.time_gran, out.max_pages), (1, 64));
+
+        let err = FuseInitOut::parse(&bytes[..7]).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
     }
 }
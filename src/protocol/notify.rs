@@ -0,0 +1,300 @@
+// Server-initiated messages (fuse_out_header.unique == 0)
+
+use super::opcodes::FuseNotifyCode;
+use super::structs::*;
+use bytemuck::Pod;
+
+/// A decoded notification. The code travels in `fuse_out_header.error`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FuseNotification {
+    /// The file behind poll handle `kh` may have become ready.
+    Poll { kh: u64 },
+    /// Cached attributes of `ino`, and its data in `offset..offset + len`,
+    /// are stale. `offset` < 0 drops only the attributes; `len` <= 0 reaches
+    /// to the end of the file.
+    InvalInode { ino: u64, offset: i64, len: i64 },
+    /// The lookup of `name` in `parent` is stale.
+    InvalEntry {
+        parent: u64,
+        name: String,
+        flags: u32,
+    },
+    /// `name` in `parent`, which pointed at `child`, was removed.
+    Delete {
+        parent: u64,
+        child: u64,
+        name: String,
+    },
+    /// New contents of `nodeid` at `offset`, to be cached.
+    Store {
+        nodeid: u64,
+        offset: u64,
+        data: Vec<u8>,
+    },
+    /// The server wants back up to `size` cached bytes of `nodeid` at
+    /// `offset`. Answered with FUSE_NOTIFY_REPLY quoting `notify_unique`.
+    Retrieve {
+        notify_unique: u64,
+        nodeid: u64,
+        offset: u64,
+        size: u32,
+    },
+    /// The server lost requests and wants them sent again.
+    Resend,
+}
+
+impl FuseNotification {
+    /// Decodes the payload of a notification with `code`. Unknown codes fail
+    /// with `ErrorKind::Unsupported`.
+    pub fn parse(code: i32, payload: &[u8]) -> std::io::Result<Self> {
+        let code = u32::try_from(code)
+            .ok()
+            .and_then(|c| FuseNotifyCode::try_from(c).ok())
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::Unsupported,
+                    format!("unknown notify code {}", code),
+                )
+            })?;
+
+        Ok(match code {
+            FuseNotifyCode::Poll => {
+                let (out, _) = read::<FuseNotifyPollWakeupOut>(payload)?;
+                Self::Poll { kh: out.kh }
+            }
+            FuseNotifyCode::InvalInode => {
+                let (out, _) = read::<FuseNotifyInvalInodeOut>(payload)?;
+                Self::InvalInode {
+                    ino: out.ino,
+                    offset: out.off,
+                    len: out.len,
+                }
+            }
+            FuseNotifyCode::InvalEntry => {
+                let (out, rest) = read::<FuseNotifyInvalEntryOut>(payload)?;
+                Self::InvalEntry {
+                    parent: out.parent,
+                    name: name(rest, out.namelen)?,
+                    flags: out.flags,
+                }
+            }
+            FuseNotifyCode::Delete => {
+                let (out, rest) = read::<FuseNotifyDeleteOut>(payload)?;
+                Self::Delete {
+                    parent: out.parent,
+                    child: out.child,
+                    name: name(rest, out.namelen)?,
+                }
+            }
+            FuseNotifyCode::Store => {
+                let (out, rest) = read::<FuseNotifyStoreOut>(payload)?;
+                let data = rest.get(..out.size as usize).ok_or_else(|| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::UnexpectedEof,
+                        format!("{} payload truncated", code),
+                    )
+                })?;
+                Self::Store {
+                    nodeid: out.nodeid,
+                    offset: out.offset,
+                    data: data.to_vec(),
+                }
+            }
+            FuseNotifyCode::Retrieve => {
+                let (out, _) = read::<FuseNotifyRetrieveOut>(payload)?;
+                Self::Retrieve {
+                    notify_unique: out.notify_unique,
+                    nodeid: out.nodeid,
+                    offset: out.offset,
+                    size: out.size,
+                }
+            }
+            FuseNotifyCode::Resend => Self::Resend,
+        })
+    }
+
+    pub fn code(&self) -> FuseNotifyCode {
+        match self {
+            Self::Poll { .. } => FuseNotifyCode::Poll,
+            Self::InvalInode { .. } => FuseNotifyCode::InvalInode,
+            Self::InvalEntry { .. } => FuseNotifyCode::InvalEntry,
+            Self::Delete { .. } => FuseNotifyCode::Delete,
+            Self::Store { .. } => FuseNotifyCode::Store,
+            Self::Retrieve { .. } => FuseNotifyCode::Retrieve,
+            Self::Resend => FuseNotifyCode::Resend,
+        }
+    }
+}
+
+/// Splits a fixed-size struct off the front of `buf`.
+fn read<S: Pod>(buf: &[u8]) -> std::io::Result<(S, &[u8])> {
+    let needed = std::mem::size_of::<S>();
+
+    if buf.len() < needed {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            format!(
+                "{} too small: got {}, need {}",
+                std::any::type_name::<S>(),
+                buf.len(),
+                needed
+            ),
+        ));
+    }
+
+    Ok((bytemuck::pod_read_unaligned(&buf[..needed]), &buf[needed..]))
+}
+
+/// The `namelen`-byte name that follows the struct (the NUL after it is
+/// not counted).
+fn name(rest: &[u8], namelen: u32) -> std::io::Result<String> {
+    let bytes = rest.get(..namelen as usize).ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            format!("notification name truncated: need {}", namelen),
+        )
+    })?;
+    Ok(String::from_utf8_lossy(bytes).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payload<S: Pod>(out: S, tail: &[u8]) -> Vec<u8> {
+        let mut buf = bytemuck::bytes_of(&out).to_vec();
+        buf.extend_from_slice(tail);
+        buf
+    }
+
+    fn delete(namelen: u32, tail: &[u8]) -> Vec<u8> {
+        let out = FuseNotifyDeleteOut {
+            parent: 1,
+            child: 7,
+            namelen,
+            padding: 0,
+        };
+        payload(out, tail)
+    }
+
+    #[test]
+    fn parses_delete() {
+        let n = FuseNotification::parse(6, &delete(4, b"file\0")).unwrap();
+        assert_eq!(
+            n,
+            FuseNotification::Delete {
+                parent: 1,
+                child: 7,
+                name: "file".into(),
+            }
+        );
+        assert_eq!(n.code(), FuseNotifyCode::Delete);
+    }
+
+    #[test]
+    fn parses_inval_entry_and_inode() {
+        let out = FuseNotifyInvalEntryOut {
+            parent: 3,
+            namelen: 1,
+            flags: 0,
+        };
+        let n = FuseNotification::parse(3, &payload(out, b"x\0")).unwrap();
+        assert_eq!(
+            n,
+            FuseNotification::InvalEntry {
+                parent: 3,
+                name: "x".into(),
+                flags: 0,
+            }
+        );
+
+        let out = FuseNotifyInvalInodeOut {
+            ino: 9,
+            off: -1,
+            len: 0,
+        };
+        let n = FuseNotification::parse(2, &payload(out, &[])).unwrap();
+        assert_eq!(
+            n,
+            FuseNotification::InvalInode {
+                ino: 9,
+                offset: -1,
+                len: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn parses_store_and_retrieve() {
+        let out = FuseNotifyStoreOut {
+            nodeid: 4,
+            offset: 10,
+            size: 3,
+            padding: 0,
+        };
+        let n = FuseNotification::parse(4, &payload(out, b"abcdef")).unwrap();
+        assert_eq!(
+            n,
+            FuseNotification::Store {
+                nodeid: 4,
+                offset: 10,
+                data: b"abc".to_vec(),
+            }
+        );
+
+        let out = FuseNotifyRetrieveOut {
+            notify_unique: 77,
+            nodeid: 4,
+            offset: 0,
+            size: 4096,
+            padding: 0,
+        };
+        let n = FuseNotification::parse(5, &payload(out, &[])).unwrap();
+        assert_eq!(
+            n,
+            FuseNotification::Retrieve {
+                notify_unique: 77,
+                nodeid: 4,
+                offset: 0,
+                size: 4096,
+            }
+        );
+    }
+
+    #[test]
+    fn truncated_name_is_an_error() {
+        let err = FuseNotification::parse(6, &delete(10, b"short")).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+
+        let out = FuseNotifyInvalEntryOut {
+            parent: 3,
+            namelen: u32::MAX,
+            flags: 0,
+        };
+        let err = FuseNotification::parse(3, &payload(out, b"x")).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn truncated_struct_and_store_data_are_errors() {
+        let err = FuseNotification::parse(6, &delete(4, b"file")[..20]).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+
+        let out = FuseNotifyStoreOut {
+            nodeid: 4,
+            offset: 0,
+            size: 100,
+            padding: 0,
+        };
+        let err = FuseNotification::parse(4, &payload(out, b"abc")).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn unknown_codes_are_unsupported() {
+        for code in [0, 99, -2] {
+            let err = FuseNotification::parse(code, &[]).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+        }
+    }
+}
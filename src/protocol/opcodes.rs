@@ -1,93 +1,178 @@
-//! Fuse opcodes as defined in fs/fuse/fuse_i.h
-
-// ========== File system operations ==========
-
-pub const FUSE_LOOKUP: u32 = 1;
-pub const FUSE_FORGET: u32 = 2; // no reply
-pub const FUSE_GETATTR: u32 = 3;
-pub const FUSE_SETATTR: u32 = 4;
-pub const FUSE_READLINK: u32 = 5;
-pub const FUSE_SYMLINK: u32 = 6;
-pub const FUSE_MKNOD: u32 = 8;
-pub const FUSE_MKDIR: u32 = 9;
-pub const FUSE_UNLINK: u32 = 10;
-pub const FUSE_RMDIR: u32 = 11;
-pub const FUSE_RENAME: u32 = 12;
-pub const FUSE_LINK: u32 = 13;
-
-// ========== File operations ==========
-
-pub const FUSE_OPEN: u32 = 14;
-pub const FUSE_READ: u32 = 15;
-pub const FUSE_WRITE: u32 = 16;
-pub const FUSE_STATFS: u32 = 17;
-pub const FUSE_RELEASE: u32 = 18;
-pub const FUSE_FSYNC: u32 = 20;
-
-// ========== Directory operations ==========
-
-pub const FUSE_SETXATTR: u32 = 21;
-pub const FUSE_GETXATTR: u32 = 22;
-pub const FUSE_LISTXATTR: u32 = 23;
-pub const FUSE_REMOVEXATTR: u32 = 24;
-
-pub const FUSE_FLUSH: u32 = 25;
-
-// ========== Session / mount operations ==========
-
-pub const FUSE_INIT: u32 = 26;
-pub const FUSE_OPENDIR: u32 = 27;
-pub const FUSE_READDIR: u32 = 28;
-pub const FUSE_RELEASEDIR: u32 = 29;
-pub const FUSE_FSYNCDIR: u32 = 30;
-
-pub const FUSE_GETLK: u32 = 31;
-pub const FUSE_SETLK: u32 = 32;
-pub const FUSE_SETLKW: u32 = 33;
-
-pub const FUSE_ACCESS: u32 = 34;
-pub const FUSE_CREATE: u32 = 35;
-
-// ========== Interrupt + IOCTL ==========
-
-pub const FUSE_INTERRUPT: u32 = 36;
-
-pub const FUSE_BMAP: u32 = 37;
-
-pub const FUSE_DESTROY: u32 = 38;
-
-// ========== FS notifications & extended ops ==========
-
-pub const FUSE_IOCTL: u32 = 39;
-
-pub const FUSE_POLL: u32 = 40; // kernel >= 3.11
-
-pub const FUSE_NOTIFY_REPLY: u32 = 41;
-
-// ========== Batch & writeback ops ==========
-
-pub const FUSE_BATCH_FORGET: u32 = 42;
-
-// ========== FUSE_READDIRPLUS ==========
-
-pub const FUSE_READDIRPLUS: u32 = 43; // kernel >= 3.13
-
-pub const FUSE_RENAME2: u32 = 44; // flags-enabled rename
-
-// ========== Symlink + contact ==========
-
-pub const FUSE_LSEEK: u32 = 45;
-
-// ========== Copy file range ==========
-
-pub const FUSE_COPY_FILE_RANGE: u32 = 46; // kernel >= 4.5
-
-// ========== Additional ops for virtiofs ==========
-
-// FALLTHROUGH_FLAGS was removed from kernel, but opcode reserved
-pub const FUSE_SETUPMAPPING: u32 = 47; // virtio-fs / dax
-pub const FUSE_REMOVEMAPPING: u32 = 48; // virtio-fs / dax
-
-// ========== Reserved opcodes (placeholders) ==========
-
-// 49–63 reserved for future expansion
+//! Fuse opcodes and notify codes as defined in include/uapi/linux/fuse.h
+//!
+//! ```
+//! use fuse_client_for_fs::protocol::opcodes::{FuseNotifyCode, FuseOpcode};
+//!
+//! // Kernel ABI numbers (enum fuse_opcode / enum fuse_notify_code)
+//! let abi = [
+//!     (FuseOpcode::Lookup, 1),
+//!     (FuseOpcode::Forget, 2),
+//!     (FuseOpcode::Mknod, 8),
+//!     (FuseOpcode::Fsync, 20),
+//!     (FuseOpcode::Init, 26),
+//!     (FuseOpcode::Create, 35),
+//!     (FuseOpcode::BatchForget, 42),
+//!     (FuseOpcode::Fallocate, 43),
+//!     (FuseOpcode::Readdirplus, 44),
+//!     (FuseOpcode::Rename2, 45),
+//!     (FuseOpcode::Lseek, 46),
+//!     (FuseOpcode::CopyFileRange, 47),
+//!     (FuseOpcode::SetupMapping, 48),
+//!     (FuseOpcode::RemoveMapping, 49),
+//!     (FuseOpcode::Syncfs, 50),
+//!     (FuseOpcode::Tmpfile, 51),
+//!     (FuseOpcode::Statx, 52),
+//!     (FuseOpcode::CuseInit, 4096),
+//! ];
+//! for (op, num) in abi {
+//!     assert_eq!(op as u32, num);
+//!     assert_eq!(FuseOpcode::try_from(num), Ok(op));
+//! }
+//!
+//! // 7 was FUSE_GETDIR, 19 is unused
+//! assert_eq!(FuseOpcode::try_from(7), Err(7));
+//! assert_eq!(FuseOpcode::try_from(19), Err(19));
+//! assert_eq!(FuseOpcode::try_from(53), Err(53));
+//!
+//! assert_eq!(FuseOpcode::CopyFileRange.to_string(), "FUSE_COPY_FILE_RANGE");
+//! assert_eq!(FuseNotifyCode::try_from(7), Ok(FuseNotifyCode::Resend));
+//! assert_eq!(FuseNotifyCode::InvalEntry.to_string(), "FUSE_NOTIFY_INVAL_ENTRY");
+//! ```
+
+use std::fmt;
+
+/// Declares a `#[repr(u32)]` enum together with `TryFrom<u32>` (the error
+/// is the unknown value) and a `name()` that returns the kernel's name.
+macro_rules! fuse_codes {
+    (
+        $(#[$meta:meta])*
+        pub enum $name:ident {
+            $($(#[$vmeta:meta])* $variant:ident = $value:literal => $kname:literal,)*
+        }
+    ) => {
+        $(#[$meta])*
+        #[repr(u32)]
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+        pub enum $name {
+            $($(#[$vmeta])* $variant = $value,)*
+        }
+
+        impl $name {
+            /// The name used in fuse.h, e.g. "FUSE_LOOKUP".
+            pub fn name(self) -> &'static str {
+                match self {
+                    $(Self::$variant => $kname,)*
+                }
+            }
+        }
+
+        impl TryFrom<u32> for $name {
+            type Error = u32;
+
+            fn try_from(value: u32) -> Result<Self, u32> {
+                match value {
+                    $($value => Ok(Self::$variant),)*
+                    other => Err(other),
+                }
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str(self.name())
+            }
+        }
+    };
+}
+
+fuse_codes! {
+    /// Opcode carried in `fuse_in_header.opcode`.
+    pub enum FuseOpcode {
+        // ========== File system operations ==========
+        Lookup = 1 => "FUSE_LOOKUP",
+        /// No reply
+        Forget = 2 => "FUSE_FORGET",
+        Getattr = 3 => "FUSE_GETATTR",
+        Setattr = 4 => "FUSE_SETATTR",
+        Readlink = 5 => "FUSE_READLINK",
+        Symlink = 6 => "FUSE_SYMLINK",
+        Mknod = 8 => "FUSE_MKNOD",
+        Mkdir = 9 => "FUSE_MKDIR",
+        Unlink = 10 => "FUSE_UNLINK",
+        Rmdir = 11 => "FUSE_RMDIR",
+        Rename = 12 => "FUSE_RENAME",
+        Link = 13 => "FUSE_LINK",
+
+        // ========== File operations ==========
+        Open = 14 => "FUSE_OPEN",
+        Read = 15 => "FUSE_READ",
+        Write = 16 => "FUSE_WRITE",
+        Statfs = 17 => "FUSE_STATFS",
+        Release = 18 => "FUSE_RELEASE",
+        Fsync = 20 => "FUSE_FSYNC",
+
+        // ========== Extended attributes ==========
+        Setxattr = 21 => "FUSE_SETXATTR",
+        Getxattr = 22 => "FUSE_GETXATTR",
+        Listxattr = 23 => "FUSE_LISTXATTR",
+        Removexattr = 24 => "FUSE_REMOVEXATTR",
+
+        Flush = 25 => "FUSE_FLUSH",
+
+        // ========== Session + directory operations ==========
+        Init = 26 => "FUSE_INIT",
+        Opendir = 27 => "FUSE_OPENDIR",
+        Readdir = 28 => "FUSE_READDIR",
+        Releasedir = 29 => "FUSE_RELEASEDIR",
+        Fsyncdir = 30 => "FUSE_FSYNCDIR",
+
+        Getlk = 31 => "FUSE_GETLK",
+        Setlk = 32 => "FUSE_SETLK",
+        Setlkw = 33 => "FUSE_SETLKW",
+
+        Access = 34 => "FUSE_ACCESS",
+        Create = 35 => "FUSE_CREATE",
+
+        // ========== Interrupt + IOCTL ==========
+        Interrupt = 36 => "FUSE_INTERRUPT",
+        Bmap = 37 => "FUSE_BMAP",
+        Destroy = 38 => "FUSE_DESTROY",
+        Ioctl = 39 => "FUSE_IOCTL",
+        Poll = 40 => "FUSE_POLL",
+        NotifyReply = 41 => "FUSE_NOTIFY_REPLY",
+        BatchForget = 42 => "FUSE_BATCH_FORGET",
+
+        // ========== Later additions ==========
+        Fallocate = 43 => "FUSE_FALLOCATE",
+        Readdirplus = 44 => "FUSE_READDIRPLUS",
+        /// Flags-enabled rename
+        Rename2 = 45 => "FUSE_RENAME2",
+        Lseek = 46 => "FUSE_LSEEK",
+        CopyFileRange = 47 => "FUSE_COPY_FILE_RANGE",
+
+        // ========== virtio-fs DAX window ==========
+        SetupMapping = 48 => "FUSE_SETUPMAPPING",
+        RemoveMapping = 49 => "FUSE_REMOVEMAPPING",
+
+        Syncfs = 50 => "FUSE_SYNCFS",
+        Tmpfile = 51 => "FUSE_TMPFILE",
+        Statx = 52 => "FUSE_STATX",
+
+        // ========== CUSE ==========
+        CuseInit = 4096 => "CUSE_INIT",
+    }
+}
+
+fuse_codes! {
+    /// Code carried in `fuse_out_header.error` of a server-initiated
+    /// notification (one with `unique == 0`).
+    pub enum FuseNotifyCode {
+        Poll = 1 => "FUSE_NOTIFY_POLL",
+        InvalInode = 2 => "FUSE_NOTIFY_INVAL_INODE",
+        InvalEntry = 3 => "FUSE_NOTIFY_INVAL_ENTRY",
+        Store = 4 => "FUSE_NOTIFY_STORE",
+        Retrieve = 5 => "FUSE_NOTIFY_RETRIEVE",
+        Delete = 6 => "FUSE_NOTIFY_DELETE",
+        Resend = 7 => "FUSE_NOTIFY_RESEND",
+    }
+}
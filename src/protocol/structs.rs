@@ -1,8 +1,83 @@
-use bytemuck::try_from_bytes;
+use bitflags::bitflags;
 use bytemuck::{Pod, Zeroable};
 use std::mem::size_of;
 
 // #[repr(C)] FUSE payload structs
+
+/// Protocol version we speak; the server answers with the one both sides
+/// use, which may be older.
+pub const FUSE_KERNEL_VERSION: u32 = 7;
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 38;
+
+bitflags! {
+    /// Capabilities negotiated by INIT: `fuse_init_in/out.flags`, with
+    /// `flags2` as the upper 32 bits.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+    pub struct FuseInitFlags: u64 {
+        const ASYNC_READ = 1 << 0;
+        const POSIX_LOCKS = 1 << 1;
+        const FILE_OPS = 1 << 2;
+        /// The server handles O_TRUNC in OPEN itself.
+        const ATOMIC_O_TRUNC = 1 << 3;
+        const EXPORT_SUPPORT = 1 << 4;
+        const BIG_WRITES = 1 << 5;
+        const DONT_MASK = 1 << 6;
+        const SPLICE_WRITE = 1 << 7;
+        const SPLICE_MOVE = 1 << 8;
+        const SPLICE_READ = 1 << 9;
+        const FLOCK_LOCKS = 1 << 10;
+        const HAS_IOCTL_DIR = 1 << 11;
+        const AUTO_INVAL_DATA = 1 << 12;
+        const DO_READDIRPLUS = 1 << 13;
+        /// The server may answer READDIRPLUS adaptively rather than for
+        /// every listing.
+        const READDIRPLUS_AUTO = 1 << 14;
+        const ASYNC_DIO = 1 << 15;
+        const WRITEBACK_CACHE = 1 << 16;
+        const NO_OPEN_SUPPORT = 1 << 17;
+        const PARALLEL_DIROPS = 1 << 18;
+        const HANDLE_KILLPRIV = 1 << 19;
+        const POSIX_ACL = 1 << 20;
+        const ABORT_ERROR = 1 << 21;
+        /// `max_pages` in the reply is valid.
+        const MAX_PAGES = 1 << 22;
+        const CACHE_SYMLINKS = 1 << 23;
+        const NO_OPENDIR_SUPPORT = 1 << 24;
+        const EXPLICIT_INVAL_DATA = 1 << 25;
+        /// `map_alignment` in the reply is valid.
+        const MAP_ALIGNMENT = 1 << 26;
+        const SUBMOUNTS = 1 << 27;
+        const HANDLE_KILLPRIV_V2 = 1 << 28;
+        const SETXATTR_EXT = 1 << 29;
+        /// `flags2` is valid; always sent, since we have it.
+        const INIT_EXT = 1 << 30;
+        // flags2
+        const SECURITY_CTX = 1 << 32;
+        const HAS_INODE_DAX = 1 << 33;
+        const CREATE_SUPP_GROUP = 1 << 34;
+        const HAS_EXPIRE_ONLY = 1 << 35;
+        const DIRECT_IO_ALLOW_MMAP = 1 << 36;
+        /// `max_stack_depth` in the reply is valid.
+        const PASSTHROUGH = 1 << 37;
+        const NO_EXPORT_SUPPORT = 1 << 38;
+        const HAS_RESEND = 1 << 39;
+        const ALLOW_IDMAP = 1 << 40;
+    }
+}
+
+impl FuseInitFlags {
+    /// Combines the two 32-bit halves carried on the wire. Bits we have no
+    /// name for are kept.
+    pub fn from_wire(flags: u32, flags2: u32) -> Self {
+        Self::from_bits_retain(((flags2 as u64) << 32) | flags as u64)
+    }
+
+    /// `(flags, flags2)`
+    pub fn to_wire(self) -> (u32, u32) {
+        (self.bits() as u32, (self.bits() >> 32) as u32)
+    }
+}
+
 #[repr(C)]
 #[derive(Debug, Clone, Copy, Pod, Zeroable)]
 pub struct FuseInitIn {
@@ -10,15 +85,21 @@ pub struct FuseInitIn {
     pub minor: u32,
     pub max_readahead: u32,
     pub flags: u32,
+    pub flags2: u32,
+    pub unused: [u32; 11],
 }
 
 impl FuseInitIn {
-    pub fn new() -> Self {
+    pub fn new(flags: FuseInitFlags) -> Self {
+        let (flags, flags2) = (flags | FuseInitFlags::INIT_EXT).to_wire();
+
         Self {
-            major: 7,  // Kernel-major protocol version
-            minor: 31, // Minor version used widely; 31-36 OK
+            major: FUSE_KERNEL_VERSION,
+            minor: FUSE_KERNEL_MINOR_VERSION,
             max_readahead: 0x20000,
-            flags: 0, // No flags requested
+            flags,
+            flags2,
+            unused: [0; 11],
         }
     }
 }
@@ -33,21 +114,34 @@ pub struct FuseInitOut {
     pub max_background: u16,
     pub congestion_threshold: u16,
     pub max_write: u32,
-    // There may be more fields! We ignore optional ones for now.
+    pub time_gran: u32, // ns; minor >= 23
+    pub max_pages: u16,
+    pub map_alignment: u16,
+    pub flags2: u32,
+    pub max_stack_depth: u32,
+    pub unused: [u32; 6],
 }
 
+/// Reply size of servers older than minor 5; the major/minor pair alone.
+const FUSE_COMPAT_INIT_OUT_SIZE: usize = 8;
+
 impl FuseInitOut {
+    /// Older servers send a shorter reply (24 bytes before minor 23, 8
+    /// before minor 5); the fields they leave out read as zero.
     pub fn parse(buf: &[u8]) -> std::io::Result<Self> {
-        if buf.len() < std::mem::size_of::<Self>() {
+        if buf.len() < FUSE_COMPAT_INIT_OUT_SIZE {
             return Err(std::io::Error::new(
                 std::io::ErrorKind::UnexpectedEof,
-                "fuse_init_out too small",
+                format!("fuse_init_out too small: {} bytes", buf.len()),
             ));
         }
 
-        let out = bytemuck::from_bytes::<FuseInitOut>(&buf[..]);
+        let mut out = FuseInitOut::zeroed();
+        let dst = bytemuck::bytes_of_mut(&mut out);
+        let n = buf.len().min(dst.len());
+        dst[..n].copy_from_slice(&buf[..n]);
 
-        Ok(*out)
+        Ok(out)
     }
 }
 
@@ -123,6 +217,26 @@ impl FuseOpenIn {
     }
 }
 
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+pub struct FuseCreateIn {
+    pub flags: u32,
+    pub mode: u32,
+    pub umask: u32,
+    pub open_flags: u32,
+}
+
+impl FuseCreateIn {
+    pub fn new(flags: u32, mode: u32, umask: u32) -> Self {
+        Self {
+            flags,
+            mode,
+            umask,
+            open_flags: 0,
+        }
+    }
+}
+
 #[repr(C)]
 #[derive(Debug, Clone, Copy, Pod, Zeroable)]
 pub struct FuseOpenOut {
@@ -141,7 +255,8 @@ impl FuseOpenOut {
             ));
         }
 
-        Ok(*bytemuck::from_bytes::<FuseOpenOut>(&buf))
+        let size = std::mem::size_of::<Self>();
+        Ok(*bytemuck::from_bytes::<FuseOpenOut>(&buf[..size]))
     }
 }
 
@@ -157,6 +272,267 @@ pub struct FuseReadIn {
     pub padding: u32,
 }
 
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+pub struct FuseWriteIn {
+    pub fh: u64,
+    pub offset: u64,
+    pub size: u32,
+    pub write_flags: u32,
+    pub lock_owner: u64,
+    pub flags: u32,
+    pub padding: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+pub struct FuseWriteOut {
+    pub size: u32,
+    pub padding: u32,
+}
+
+impl FuseWriteOut {
+    pub fn parse(buf: &[u8]) -> std::io::Result<Self> {
+        let needed = std::mem::size_of::<Self>();
+
+        if buf.len() < needed {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!("FuseWriteOut too small: got {}, need {}", buf.len(), needed),
+            ));
+        }
+
+        Ok(*bytemuck::from_bytes::<FuseWriteOut>(&buf[..needed]))
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+pub struct FuseInterruptIn {
+    pub unique: u64, // the request to interrupt
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+pub struct FuseForgetIn {
+    pub nlookup: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+pub struct FuseForgetOne {
+    pub nodeid: u64,
+    pub nlookup: u64,
+}
+
+// payload = batch_forget_in | forget_one[count]
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+pub struct FuseBatchForgetIn {
+    pub count: u32,
+    pub dummy: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+pub struct FuseCopyFileRangeIn {
+    pub fh_in: u64,
+    pub off_in: u64,
+    pub nodeid_out: u64,
+    pub fh_out: u64,
+    pub off_out: u64,
+    pub len: u64,
+    pub flags: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+pub struct FuseLseekIn {
+    pub fh: u64,
+    pub offset: u64,
+    pub whence: u32, // only SEEK_DATA / SEEK_HOLE reach the server
+    pub padding: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+pub struct FuseLseekOut {
+    pub offset: u64,
+}
+
+impl FuseLseekOut {
+    pub fn parse(buf: &[u8]) -> std::io::Result<Self> {
+        let needed = std::mem::size_of::<Self>();
+
+        if buf.len() < needed {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!("FuseLseekOut too small: got {}, need {}", buf.len(), needed),
+            ));
+        }
+
+        Ok(*bytemuck::from_bytes::<FuseLseekOut>(&buf[..needed]))
+    }
+}
+
+// FUSE_IOCTL flags (fuse_ioctl_in.flags / fuse_ioctl_out.flags)
+pub const FUSE_IOCTL_COMPAT: u32 = 1 << 0;
+pub const FUSE_IOCTL_UNRESTRICTED: u32 = 1 << 1;
+pub const FUSE_IOCTL_RETRY: u32 = 1 << 2;
+pub const FUSE_IOCTL_32BIT: u32 = 1 << 3;
+pub const FUSE_IOCTL_DIR: u32 = 1 << 4;
+pub const FUSE_IOCTL_COMPAT_X32: u32 = 1 << 5;
+
+/// Most iovecs a RETRY reply may ask for (in + out together).
+pub const FUSE_IOCTL_MAX_IOV: usize = 256;
+
+// payload = ioctl_in | in_size bytes of input
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+pub struct FuseIoctlIn {
+    pub fh: u64,
+    pub flags: u32,
+    pub cmd: u32,
+    pub arg: u64,
+    pub in_size: u32,
+    pub out_size: u32,
+}
+
+// reply = ioctl_out | out data, or ioctl_out | iovec[in_iovs + out_iovs]
+// when FUSE_IOCTL_RETRY is set
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+pub struct FuseIoctlOut {
+    pub result: i32,
+    pub flags: u32,
+    pub in_iovs: u32,
+    pub out_iovs: u32,
+}
+
+impl FuseIoctlOut {
+    pub fn parse(buf: &[u8]) -> std::io::Result<Self> {
+        let needed = std::mem::size_of::<Self>();
+
+        if buf.len() < needed {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!("FuseIoctlOut too small: got {}, need {}", buf.len(), needed),
+            ));
+        }
+
+        Ok(*bytemuck::from_bytes::<FuseIoctlOut>(&buf[..needed]))
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+pub struct FuseIoctlIovec {
+    pub base: u64,
+    pub len: u64,
+}
+
+/// Ask the server to send FUSE_NOTIFY_POLL for `kh` once the file's
+/// readiness changes.
+pub const FUSE_POLL_SCHEDULE_NOTIFY: u32 = 1 << 0;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+pub struct FusePollIn {
+    pub fh: u64,
+    pub kh: u64, // our handle, echoed back in the wakeup
+    pub flags: u32,
+    pub events: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+pub struct FusePollOut {
+    pub revents: u32,
+    pub padding: u32,
+}
+
+impl FusePollOut {
+    pub fn parse(buf: &[u8]) -> std::io::Result<Self> {
+        let needed = std::mem::size_of::<Self>();
+
+        if buf.len() < needed {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!("FusePollOut too small: got {}, need {}", buf.len(), needed),
+            ));
+        }
+
+        Ok(*bytemuck::from_bytes::<FusePollOut>(&buf[..needed]))
+    }
+}
+
+// payload of FUSE_NOTIFY_POLL
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+pub struct FuseNotifyPollWakeupOut {
+    pub kh: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+pub struct FuseNotifyInvalInodeOut {
+    pub ino: u64,
+    pub off: i64,
+    pub len: i64, // <= 0 means to the end of the file
+}
+
+// payload = inval_entry_out | name | NUL
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+pub struct FuseNotifyInvalEntryOut {
+    pub parent: u64,
+    pub namelen: u32,
+    pub flags: u32,
+}
+
+// payload = delete_out | name | NUL
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+pub struct FuseNotifyDeleteOut {
+    pub parent: u64,
+    pub child: u64,
+    pub namelen: u32,
+    pub padding: u32,
+}
+
+// payload = store_out | size bytes of data
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+pub struct FuseNotifyStoreOut {
+    pub nodeid: u64,
+    pub offset: u64,
+    pub size: u32,
+    pub padding: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+pub struct FuseNotifyRetrieveOut {
+    pub notify_unique: u64,
+    pub nodeid: u64,
+    pub offset: u64,
+    pub size: u32,
+    pub padding: u32,
+}
+
+// FUSE_NOTIFY_REPLY body, sent with unique = notify_unique;
+// payload = retrieve_in | size bytes of data
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+pub struct FuseNotifyRetrieveIn {
+    pub dummy1: u64,
+    pub offset: u64,
+    pub size: u32,
+    pub dummy2: u32,
+    pub dummy3: u64,
+    pub dummy4: u64,
+}
+
 #[repr(C)]
 #[derive(Debug, Clone, Copy, Pod, Zeroable)]
 pub struct FuseReleaseIn {
@@ -166,6 +542,88 @@ pub struct FuseReleaseIn {
     pub lock_owner: u64,
 }
 
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+pub struct FuseFlushIn {
+    pub fh: u64,
+    pub unused: u32,
+    pub padding: u32,
+    pub lock_owner: u64,
+}
+
+// fuse_fsync_in.fsync_flags: only sync data, not metadata (fdatasync)
+pub const FUSE_FSYNC_FDATASYNC: u32 = 1 << 0;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+pub struct FuseFsyncIn {
+    pub fh: u64,
+    pub fsync_flags: u32,
+    pub padding: u32,
+}
+
+// fuse_release_in.release_flags: drop the flock() held through this handle
+pub const FUSE_RELEASE_FLOCK_UNLOCK: u32 = 1 << 1;
+
+/// A byte-range lock. `end` is inclusive; OFFSET_MAX means "to end of file".
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+pub struct FuseFileLock {
+    pub start: u64,
+    pub end: u64,
+    pub typ: u32, // F_RDLCK, F_WRLCK or F_UNLCK
+    pub pid: u32,
+}
+
+// fuse_lk_in.lk_flags: the request is a flock(2), not a POSIX record lock
+pub const FUSE_LK_FLOCK: u32 = 1 << 0;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+pub struct FuseLkIn {
+    pub fh: u64,
+    pub owner: u64,
+    pub lk: FuseFileLock,
+    pub lk_flags: u32,
+    pub padding: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+pub struct FuseLkOut {
+    pub lk: FuseFileLock,
+}
+
+impl FuseLkOut {
+    pub fn parse(buf: &[u8]) -> std::io::Result<Self> {
+        let needed = std::mem::size_of::<Self>();
+
+        if buf.len() < needed {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!("FuseLkOut too small: got {}, need {}", buf.len(), needed),
+            ));
+        }
+
+        Ok(*bytemuck::from_bytes::<FuseLkOut>(&buf[..needed]))
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+pub struct FuseSyncfsIn {
+    pub padding: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+pub struct FuseMknodIn {
+    pub mode: u32, // includes the S_IFMT file type
+    pub rdev: u32,
+    pub umask: u32,
+    pub padding: u32,
+}
+
 #[repr(C)]
 #[derive(Clone, Copy, Pod, Zeroable)]
 pub struct FuseMkdirIn {
@@ -179,6 +637,53 @@ impl FuseMkdirIn {
     }
 }
 
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+pub struct FuseRenameIn {
+    pub newdir: u64,
+}
+
+// renameat2(2) flags carried by FUSE_RENAME2
+pub const RENAME_NOREPLACE: u32 = 1 << 0;
+pub const RENAME_EXCHANGE: u32 = 1 << 1;
+pub const RENAME_WHITEOUT: u32 = 1 << 2;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+pub struct FuseRename2In {
+    pub newdir: u64,
+    pub flags: u32,
+    pub padding: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+pub struct FuseFallocateIn {
+    pub fh: u64,
+    pub offset: u64,
+    pub length: u64,
+    pub mode: u32,
+    pub padding: u32,
+}
+
+// fallocate(2) mode bits carried by FUSE_FALLOCATE
+pub const FALLOC_FL_KEEP_SIZE: u32 = 0x01;
+pub const FALLOC_FL_PUNCH_HOLE: u32 = 0x02;
+pub const FALLOC_FL_ZERO_RANGE: u32 = 0x10;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+pub struct FuseAccessIn {
+    pub mask: u32, // R_OK | W_OK | X_OK, or F_OK
+    pub padding: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+pub struct FuseLinkIn {
+    pub oldnodeid: u64,
+}
+
 #[repr(C)]
 #[derive(Debug, Clone, Copy, Pod, Zeroable)]
 pub struct FuseGetattrIn {
@@ -197,6 +702,54 @@ impl FuseGetattrIn {
     }
 }
 
+// fuse_setattr_in.valid bits: which fields of the request are meaningful
+pub const FATTR_MODE: u32 = 1 << 0;
+pub const FATTR_UID: u32 = 1 << 1;
+pub const FATTR_GID: u32 = 1 << 2;
+pub const FATTR_SIZE: u32 = 1 << 3;
+pub const FATTR_ATIME: u32 = 1 << 4;
+pub const FATTR_MTIME: u32 = 1 << 5;
+pub const FATTR_FH: u32 = 1 << 6;
+pub const FATTR_ATIME_NOW: u32 = 1 << 7;
+pub const FATTR_MTIME_NOW: u32 = 1 << 8;
+pub const FATTR_LOCKOWNER: u32 = 1 << 9;
+pub const FATTR_CTIME: u32 = 1 << 10;
+pub const FATTR_KILL_SUIDGID: u32 = 1 << 11;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+pub struct FuseSetattrIn {
+    pub valid: u32,
+    pub padding: u32,
+    pub fh: u64,
+    pub size: u64,
+    pub lock_owner: u64,
+    pub atime: u64,
+    pub mtime: u64,
+    pub ctime: u64,
+    pub atimensec: u32,
+    pub mtimensec: u32,
+    pub ctimensec: u32,
+    pub mode: u32,
+    pub unused4: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub unused5: u32,
+}
+
+impl FuseSetattrIn {
+    /// An empty request; set fields and their FATTR_* bit in `valid`.
+    pub fn new() -> Self {
+        Self::zeroed()
+    }
+}
+
+impl Default for FuseSetattrIn {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[repr(C)]
 #[derive(Debug, Clone, Copy, Pod, Zeroable)]
 pub struct FuseAttrOut {
@@ -229,8 +782,102 @@ impl FuseAttrOut {
     }
 }
 
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+pub struct FuseKstatfs {
+    pub blocks: u64,
+    pub bfree: u64,
+    pub bavail: u64,
+    pub files: u64,
+    pub ffree: u64,
+    pub bsize: u32,
+    pub namelen: u32,
+    pub frsize: u32,
+    pub padding: u32,
+    pub spare: [u32; 6],
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+pub struct FuseStatfsOut {
+    pub st: FuseKstatfs,
+}
+
+impl FuseStatfsOut {
+    pub fn parse(buf: &[u8]) -> std::io::Result<Self> {
+        let needed = std::mem::size_of::<Self>();
+
+        if buf.len() < needed {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!(
+                    "FuseStatfsOut too small: got {}, need {}",
+                    buf.len(),
+                    needed
+                ),
+            ));
+        }
+
+        Ok(*bytemuck::from_bytes::<FuseStatfsOut>(&buf[..needed]))
+    }
+}
+
+// setxattr(2) flags
+pub const XATTR_CREATE: u32 = 1;
+pub const XATTR_REPLACE: u32 = 2;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+pub struct FuseSetxattrIn {
+    pub size: u32,
+    pub flags: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+pub struct FuseGetxattrIn {
+    pub size: u32,
+    pub padding: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+pub struct FuseGetxattrOut {
+    pub size: u32,
+    pub padding: u32,
+}
+
+impl FuseGetxattrOut {
+    pub fn parse(buf: &[u8]) -> std::io::Result<Self> {
+        let needed = std::mem::size_of::<Self>();
+
+        if buf.len() < needed {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!(
+                    "FuseGetxattrOut too small: got {}, need {}",
+                    buf.len(),
+                    needed
+                ),
+            ));
+        }
+
+        Ok(*bytemuck::from_bytes::<FuseGetxattrOut>(&buf[..needed]))
+    }
+}
+
 use std::io;
 
+/// Fixed part of `struct fuse_dirent`; the name and padding to 8 bytes follow.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+struct FuseDirent {
+    ino: u64,
+    off: u64,
+    namelen: u32,
+    typ: u32,
+}
+
 #[derive(Debug)]
 pub struct DirEntry {
     pub ino: u64,
@@ -238,38 +885,48 @@ pub struct DirEntry {
     pub namelen: u32,
     pub typ: u32,
     pub name: String,
+    /// Lookup result carried by READDIRPLUS. `None` for plain READDIR, and
+    /// for entries the server sent without attributes (nodeid 0).
+    pub entry: Option<FuseEntryOut>,
 }
 
 impl DirEntry {
     pub fn parse_dirents(buf: &[u8]) -> io::Result<Vec<DirEntry>> {
+        Self::parse_records(buf, false)
+    }
+
+    /// struct fuse_direntplus { fuse_entry_out entry_out; fuse_dirent dirent; }
+    pub fn parse_direntplus(buf: &[u8]) -> io::Result<Vec<DirEntry>> {
+        Self::parse_records(buf, true)
+    }
+
+    fn parse_records(buf: &[u8], plus: bool) -> io::Result<Vec<DirEntry>> {
         let mut entries = Vec::new();
         let mut pos = 0usize;
+        let entry_size = if plus { size_of::<FuseEntryOut>() } else { 0 };
+        let dirent_hdr_size = size_of::<FuseDirent>(); // 24
 
-        let U64 = size_of::<u64>();
-        let U32 = size_of::<u32>();
-
-        // struct fuse_dirent {
-        //   u64 ino;
-        //   u64 off;
-        //   u32 namelen;
-        //   u32 type;
-        //   char name[];
-        // }  // then 8-byte aligned
-        let dirent_hdr_size: usize = U64 + U64 + U32 + U32; // 24
+        // Records are 8-byte aligned relative to the reply, not in memory,
+        // so the fixed parts are copied out rather than cast in place.
+        while pos + entry_size + dirent_hdr_size <= buf.len() {
+            // ---- entry_out prefix (READDIRPLUS only) ----
+            let entry = if plus {
+                let e: FuseEntryOut = bytemuck::pod_read_unaligned(&buf[pos..pos + entry_size]);
+                pos += entry_size;
+                Some(e).filter(|e| e.nodeid != 0)
+            } else {
+                None
+            };
 
-        while pos + dirent_hdr_size <= buf.len() {
             // ---- Read fixed header fields ----
-            let ino = u64::from_le_bytes(buf[pos..pos + U64].try_into().unwrap());
-            pos += U64;
-
-            let off = u64::from_le_bytes(buf[pos..pos + U64].try_into().unwrap());
-            pos += U64;
-
-            let namelen = u32::from_le_bytes(buf[pos..pos + U32].try_into().unwrap());
-            pos += U32;
-
-            let typ = u32::from_le_bytes(buf[pos..pos + U32].try_into().unwrap());
-            pos += U32;
+            let rec_start = pos;
+            let FuseDirent {
+                ino,
+                off,
+                namelen,
+                typ,
+            } = bytemuck::pod_read_unaligned(&buf[pos..pos + dirent_hdr_size]);
+            pos += dirent_hdr_size;
 
             // Now pos = start of the name field
             let name_start = pos;
@@ -287,18 +944,99 @@ impl DirEntry {
                 namelen,
                 typ,
                 name,
+                entry,
             });
 
-            pos = name_end;
-
             // ---- Record alignment ----
             // rec_len = ALIGN( hdr_size + namelen )
             let rec_len = (dirent_hdr_size + namelen as usize + 7) & !7;
 
-            pos = pos - dirent_hdr_size; // rewind to start of this record
-            pos += rec_len; // skip whole aligned record
+            pos = rec_start + rec_len; // skip whole aligned record
         }
 
         Ok(entries)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dirent(ino: u64, off: u64, name: &str) -> Vec<u8> {
+        let mut rec = Vec::new();
+        rec.extend_from_slice(&ino.to_le_bytes());
+        rec.extend_from_slice(&off.to_le_bytes());
+        rec.extend_from_slice(&(name.len() as u32).to_le_bytes());
+        rec.extend_from_slice(&(libc::DT_REG as u32).to_le_bytes());
+        rec.extend_from_slice(name.as_bytes());
+        rec.resize(rec.len().next_multiple_of(8), 0);
+        rec
+    }
+
+    fn direntplus(nodeid: u64, off: u64, name: &str) -> Vec<u8> {
+        let mut entry = FuseEntryOut::zeroed();
+        entry.nodeid = nodeid;
+        entry.attr.ino = nodeid;
+        entry.attr.size = 42;
+
+        let mut rec = bytemuck::bytes_of(&entry).to_vec();
+        rec.extend(dirent(nodeid, off, name));
+        rec
+    }
+
+    #[test]
+    fn direntplus_carries_entries() {
+        let mut buf = direntplus(2, 1, "a");
+        buf.extend(direntplus(3, 2, "longer-name"));
+
+        let entries = DirEntry::parse_direntplus(&buf).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].name, "a");
+        assert_eq!(entries[1].name, "longer-name");
+        assert_eq!(entries[1].offset, 2);
+
+        let entry = entries[1].entry.unwrap();
+        assert_eq!((entry.nodeid, entry.attr.size), (3, 42));
+    }
+
+    #[test]
+    fn direntplus_without_nodeid_has_no_entry() {
+        let entries = DirEntry::parse_direntplus(&direntplus(0, 1, "x")).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert!(entries[0].entry.is_none());
+    }
+
+    #[test]
+    fn direntplus_stops_at_truncated_record() {
+        let mut buf = direntplus(2, 1, "a");
+        let mut second = direntplus(3, 2, "bcdefgh");
+        second.truncate(second.len() - 4);
+        buf.extend(second);
+
+        let entries = DirEntry::parse_direntplus(&buf).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].name, "a");
+    }
+
+    #[test]
+    fn plain_dirents_have_no_entry() {
+        let mut buf = dirent(2, 1, "a");
+        buf.extend(dirent(3, 2, "b"));
+
+        let entries = DirEntry::parse_dirents(&buf).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert!(entries.iter().all(|e| e.entry.is_none()));
+    }
+
+    #[test]
+    fn direntplus_parses_from_unaligned_buffer() {
+        // A reply payload can start at any address; shift it off alignment.
+        let mut buf = vec![0u8];
+        buf.extend(direntplus(2, 1, "a"));
+        buf.extend(direntplus(3, 2, "b"));
+
+        let entries = DirEntry::parse_direntplus(&buf[1..]).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[1].entry.unwrap().nodeid, 3);
+    }
+}
@@ -1,20 +1,77 @@
 use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 
+use crate::protocol::{
+    FALLOC_FL_KEEP_SIZE, FALLOC_FL_PUNCH_HOLE, FALLOC_FL_ZERO_RANGE, RENAME_EXCHANGE,
+    RENAME_NOREPLACE, XATTR_CREATE, XATTR_REPLACE,
+};
 use crate::transport::common::FuseTransport;
+use crate::util::error::errno_of;
 use crate::virtiofs::VirtioFsImpl;
-use crate::virtiofs::structs::FileStat;
+use crate::virtiofs::structs::{Fd, FileStat, SeekFrom, SetTime};
+
+/// Set by Ctrl-C; the request in flight gets a FUSE_INTERRUPT.
+static CANCEL: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_sigint(_sig: libc::c_int) {
+    CANCEL.store(true, Ordering::SeqCst);
+}
+
+/// Makes Ctrl-C interrupt the running command instead of killing us, for
+/// as long as it is alive; the previous action comes back on drop.
+struct SigintHandler {
+    old: libc::sigaction,
+}
+
+impl SigintHandler {
+    fn install() -> Self {
+        // No SA_RESTART, so a wait for a reply wakes up at once.
+        unsafe {
+            let mut sa: libc::sigaction = std::mem::zeroed();
+            sa.sa_sigaction = on_sigint as *const () as libc::sighandler_t;
+            libc::sigemptyset(&mut sa.sa_mask);
+
+            let mut old: libc::sigaction = std::mem::zeroed();
+            libc::sigaction(libc::SIGINT, &sa, &mut old);
+            Self { old }
+        }
+    }
+}
+
+impl Drop for SigintHandler {
+    fn drop(&mut self) {
+        unsafe {
+            libc::sigaction(libc::SIGINT, &self.old, std::ptr::null_mut());
+        }
+    }
+}
 
 pub struct FuseShell<T: FuseTransport> {
     vfs: VirtioFsImpl<T>,
 }
 
 impl<T: FuseTransport> FuseShell<T> {
-    pub fn new(vfs: VirtioFsImpl<T>) -> Self {
+    pub fn new(mut vfs: VirtioFsImpl<T>) -> Self {
+        vfs.set_cancel_flag(&CANCEL);
+
         Self { vfs }
     }
 
+    /// Reads and runs commands until EOF or `exit`. Ctrl-C cancels the
+    /// command in flight while this runs.
     pub fn run(&mut self) -> io::Result<()> {
+        let _sigint = SigintHandler::install();
+
         loop {
+            // Between commands nothing holds on to inodes.
+            if let Err(e) = self.vfs.forget_unused() {
+                eprintln!("forget: {}", e);
+            }
+            if let Err(e) = self.vfs.process_notifications() {
+                eprintln!("notify: {}", e);
+            }
+
             print!("fuse:{}> ", self.vfs.getcwd().display());
             std::io::stdout().flush()?;
 
@@ -28,6 +85,9 @@ impl<T: FuseTransport> FuseShell<T> {
                 continue;
             }
 
+            // A Ctrl-C at the prompt must not cancel the next command.
+            CANCEL.store(false, Ordering::SeqCst);
+
             let mut parts = line.split_whitespace();
             let cmd = parts.next().unwrap();
             let args: Vec<&str> = parts.collect();
@@ -35,6 +95,7 @@ impl<T: FuseTransport> FuseShell<T> {
             match cmd {
                 "ls" => {
                     let long = args.contains(&"-l");
+                    let check = args.contains(&"--access");
                     let path = args
                         .iter()
                         .find(|a| !a.starts_with('-'))
@@ -42,7 +103,7 @@ impl<T: FuseTransport> FuseShell<T> {
                         .unwrap_or(".");
 
                     let res = if long {
-                        self.cmd_ls_long(path)
+                        self.cmd_ls_long(path, check)
                     } else {
                         self.cmd_ls_short(path)
                     };
@@ -57,23 +118,164 @@ impl<T: FuseTransport> FuseShell<T> {
                         println!("Usage: mkdir <path>");
                         continue;
                     }
-                    if let Err(e) = self.vfs.mkdir(args[0], libc::S_IFDIR as u32 | 0o755) {
+                    if let Err(e) = self.vfs.mkdir(args[0], libc::S_IFDIR | 0o755) {
                         eprintln!("mkdir: {}", e);
                     }
                 }
 
+                "rm" => {
+                    let recursive = args.iter().any(|a| *a == "-r" || *a == "-R");
+                    let paths: Vec<&str> = args
+                        .iter()
+                        .filter(|a| !a.starts_with('-'))
+                        .copied()
+                        .collect();
+
+                    if paths.is_empty() {
+                        println!("Usage: rm [-r] <path>...");
+                        continue;
+                    }
+                    for path in paths {
+                        if recursive {
+                            self.cmd_rm_recursive(path);
+                        } else if let Err(e) = self.vfs.remove_file(path) {
+                            eprintln!("rm: {}: {}", path, e);
+                        }
+                    }
+                }
+
+                "rmdir" => {
+                    if args.is_empty() {
+                        println!("Usage: rmdir <path>...");
+                        continue;
+                    }
+                    for path in &args {
+                        if let Err(e) = self.vfs.remove_dir(path) {
+                            eprintln!("rmdir: {}: {}", path, e);
+                        }
+                    }
+                }
+
+                "mv" => {
+                    let mut flags = 0;
+                    if args.contains(&"-n") {
+                        flags |= RENAME_NOREPLACE;
+                    }
+                    if args.contains(&"--exchange") {
+                        flags |= RENAME_EXCHANGE;
+                    }
+                    let paths: Vec<&str> = args
+                        .iter()
+                        .filter(|a| !a.starts_with('-'))
+                        .copied()
+                        .collect();
+
+                    if paths.len() < 2 {
+                        println!("Usage: mv [-n] [--exchange] <src>... <dst>");
+                        continue;
+                    }
+                    let (dst, srcs) = paths.split_last().unwrap();
+                    self.cmd_mv(srcs, dst, flags);
+                }
+
+                "cp" => {
+                    let recursive = args.contains(&"-r") || args.contains(&"-R");
+                    let paths: Vec<&str> = args
+                        .iter()
+                        .filter(|a| !a.starts_with('-'))
+                        .copied()
+                        .collect();
+
+                    if paths.len() < 2 {
+                        println!("Usage: cp [-r] <src>... <dst>");
+                        continue;
+                    }
+                    let (dst, srcs) = paths.split_last().unwrap();
+                    self.cmd_cp(srcs, dst, recursive);
+                }
+
+                "mkfifo" => {
+                    if args.is_empty() {
+                        println!("Usage: mkfifo <path>...");
+                        continue;
+                    }
+                    for path in &args {
+                        if let Err(e) = self.vfs.mknod(path, libc::S_IFIFO | 0o666, 0) {
+                            eprintln!("mkfifo: {}: {}", path, e);
+                        }
+                    }
+                }
+
+                "mknod" => {
+                    // mknod <path> p | mknod <path> c|b <major> <minor>
+                    let (path, typ, major, minor) = match args.as_slice() {
+                        [path, "p"] => (*path, libc::S_IFIFO, "0", "0"),
+                        [path, "c", major, minor] => (*path, libc::S_IFCHR, *major, *minor),
+                        [path, "b", major, minor] => (*path, libc::S_IFBLK, *major, *minor),
+                        _ => {
+                            println!("Usage: mknod <path> p | mknod <path> c|b <major> <minor>");
+                            continue;
+                        }
+                    };
+                    let (Ok(major), Ok(minor)) = (major.parse(), minor.parse()) else {
+                        eprintln!("mknod: invalid device number");
+                        continue;
+                    };
+                    let rdev = VirtioFsImpl::<T>::makedev(major, minor);
+                    if let Err(e) = self.vfs.mknod(path, typ | 0o666, rdev) {
+                        eprintln!("mknod: {}: {}", path, e);
+                    }
+                }
+
                 "stat" => {
                     if args.is_empty() {
                         println!("Usage: stat <path>");
                         continue;
                     }
                     let path = args[0];
-                    match self.vfs.stat(path) {
-                        Ok(st) => self.cmd_stat(st),
+                    match self.vfs.lstat(path) {
+                        Ok(st) => {
+                            let link = self.link_suffix(path, &st);
+                            println!("  File: {}{}", path, link);
+                            self.cmd_stat(st)
+                        }
                         Err(e) => eprintln!("stat: {}", e),
                     }
                 }
 
+                "ln" => {
+                    let symbolic = args.contains(&"-s");
+                    let paths: Vec<&str> = args
+                        .iter()
+                        .filter(|a| !a.starts_with('-'))
+                        .copied()
+                        .collect();
+
+                    if paths.len() != 2 {
+                        println!("Usage: ln [-s] <target> <link>");
+                        continue;
+                    }
+                    let res = if symbolic {
+                        self.vfs.symlink(paths[0], paths[1])
+                    } else {
+                        self.vfs.hard_link(paths[0], paths[1]).map(|_| ())
+                    };
+                    if let Err(e) = res {
+                        eprintln!("ln: {}: {}", paths[1], e);
+                    }
+                }
+
+                "readlink" => {
+                    if args.is_empty() {
+                        println!("Usage: readlink <path>");
+                        continue;
+                    }
+                    match self.vfs.readlink(args[0]) {
+                        Ok(target) => println!("{}", target),
+                        Err(e) => eprintln!("readlink: {}: {}", args[0], e),
+                    }
+                }
+
                 "cat" => {
                     if args.is_empty() {
                         println!("Usage: cat <path>");
@@ -84,6 +286,151 @@ impl<T: FuseTransport> FuseShell<T> {
                     }
                 }
 
+                "write" | "append" => {
+                    if args.len() < 2 {
+                        println!("Usage: {} <path> <text...>", cmd);
+                        continue;
+                    }
+                    let text = format!("{}\n", args[1..].join(" "));
+                    if let Err(e) = self.cmd_write(args[0], text.as_bytes(), cmd == "append") {
+                        eprintln!("{}: {}", cmd, e);
+                    }
+                }
+
+                "touch" => {
+                    // touch [-d <date>] <path>...
+                    let mut time = None;
+                    let mut paths = Vec::new();
+                    let mut it = args.iter();
+                    while let Some(a) = it.next() {
+                        if *a == "-d" {
+                            time = it.next().copied();
+                        } else {
+                            paths.push(*a);
+                        }
+                    }
+
+                    if paths.is_empty() {
+                        println!("Usage: touch [-d <date>] <path>...");
+                        continue;
+                    }
+                    let when = match time.map(Self::parse_date) {
+                        None => None,
+                        Some(Some(t)) => Some(t),
+                        Some(None) => {
+                            eprintln!("touch: invalid date (use YYYY-MM-DD[THH:MM:SS] or @secs)");
+                            continue;
+                        }
+                    };
+                    for path in paths {
+                        if let Err(e) = self.cmd_touch(path, when) {
+                            eprintln!("touch: {}: {}", path, e);
+                        }
+                    }
+                }
+
+                "chmod" => {
+                    if args.len() < 2 {
+                        println!("Usage: chmod <octal-mode> <path>...");
+                        continue;
+                    }
+                    let Ok(mode) = u32::from_str_radix(args[0], 8) else {
+                        eprintln!("chmod: invalid mode: {}", args[0]);
+                        continue;
+                    };
+                    for path in &args[1..] {
+                        if let Err(e) = self.vfs.set_permissions(path, mode) {
+                            eprintln!("chmod: {}: {}", path, e);
+                        }
+                    }
+                }
+
+                "chown" => {
+                    if args.len() < 2 {
+                        println!("Usage: chown <uid>[:<gid>] <path>...");
+                        continue;
+                    }
+                    // "uid", "uid:gid" or ":gid"; ids are numeric
+                    let (u, g) = args[0].split_once(':').unwrap_or((args[0], ""));
+                    let uid = if u.is_empty() { None } else { u.parse().ok() };
+                    let gid = if g.is_empty() { None } else { g.parse().ok() };
+                    if (uid.is_none() && !u.is_empty()) || (gid.is_none() && !g.is_empty()) {
+                        eprintln!("chown: invalid owner: {}", args[0]);
+                        continue;
+                    }
+                    for path in &args[1..] {
+                        if let Err(e) = self.vfs.chown(path, uid, gid) {
+                            eprintln!("chown: {}: {}", path, e);
+                        }
+                    }
+                }
+
+                "truncate" => {
+                    if args.len() < 3 || args[0] != "-s" {
+                        println!("Usage: truncate -s <size>[K|M|G] <path>...");
+                        continue;
+                    }
+                    let Some(size) = Self::parse_size(args[1]) else {
+                        eprintln!("truncate: invalid size: {}", args[1]);
+                        continue;
+                    };
+                    for path in &args[2..] {
+                        if let Err(e) = self.vfs.truncate(path, size) {
+                            eprintln!("truncate: {}: {}", path, e);
+                        }
+                    }
+                }
+
+                "fallocate" => {
+                    // fallocate [-n] [-p] [-z] [-o <offset>] -l <length> <path>
+                    let usage = "Usage: fallocate [-n] [-p] [-z] [-o <offset>] -l <length> <path>";
+                    let mut mode = 0;
+                    let mut offset = Some(0);
+                    let mut len = None;
+                    let mut path = None;
+                    let mut it = args.iter();
+                    while let Some(a) = it.next() {
+                        match *a {
+                            "-n" => mode |= FALLOC_FL_KEEP_SIZE,
+                            // punching never changes the size, like util-linux
+                            "-p" => mode |= FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE,
+                            "-z" => mode |= FALLOC_FL_ZERO_RANGE,
+                            "-o" => offset = it.next().and_then(|s| Self::parse_size(s)),
+                            "-l" => len = it.next().and_then(|s| Self::parse_size(s)),
+                            _ => path = Some(*a),
+                        }
+                    }
+
+                    let (Some(offset), Some(len), Some(path)) = (offset, len, path) else {
+                        println!("{}", usage);
+                        continue;
+                    };
+                    if let Err(e) = self.cmd_fallocate(path, offset, len, mode) {
+                        eprintln!("fallocate: {}: {}", path, e);
+                    }
+                }
+
+                "poll" => {
+                    // poll [-t <ms>] <path>: wait for the file to become readable
+                    let (timeout, path) = match args.as_slice() {
+                        ["-t", ms, path] => match ms.parse() {
+                            Ok(ms) => (Some(Duration::from_millis(ms)), *path),
+                            Err(_) => {
+                                eprintln!("poll: invalid timeout: {}", ms);
+                                continue;
+                            }
+                        },
+                        [path] => (None, *path),
+                        _ => {
+                            println!("Usage: poll [-t <ms>] <path>");
+                            continue;
+                        }
+                    };
+                    if let Err(e) = self.cmd_poll(path, timeout) {
+                        eprintln!("poll: {}: {}", path, e);
+                    }
+                }
+
                 "cd" => {
                     if args.is_empty() {
                         println!("Usage: cd <path>");
@@ -94,22 +441,117 @@ impl<T: FuseTransport> FuseShell<T> {
                     }
                 }
 
+                "getfattr" => {
+                    // getfattr [-n <name>] <path>
+                    let (name, path) = match args.as_slice() {
+                        ["-n", name, path] => (Some(*name), *path),
+                        [path] => (None, *path),
+                        _ => {
+                            println!("Usage: getfattr [-n <name>] <path>");
+                            continue;
+                        }
+                    };
+                    if let Err(e) = self.cmd_getfattr(path, name) {
+                        eprintln!("getfattr: {}: {}", path, e);
+                    }
+                }
+
+                "setfattr" => {
+                    if let Err(e) = self.cmd_setfattr(&args) {
+                        eprintln!("setfattr: {}", e);
+                    }
+                }
+
+                "lsattr" => {
+                    if args.is_empty() {
+                        println!("Usage: lsattr <path>");
+                        continue;
+                    }
+                    match self.vfs.list_xattr(args[0]) {
+                        Ok(names) => {
+                            for name in names {
+                                println!("{}", name);
+                            }
+                        }
+                        Err(e) => eprintln!("lsattr: {}: {}", args[0], e),
+                    }
+                }
+
+                "sync" => {
+                    if let Err(e) = self.vfs.sync_all() {
+                        eprintln!("sync: {}", e);
+                    }
+                }
+
+                "fsync" => {
+                    let datasync = args.contains(&"-d");
+                    let Some(path) = args.iter().find(|a| !a.starts_with('-')).copied() else {
+                        println!("Usage: fsync [-d] <path>");
+                        continue;
+                    };
+                    if let Err(e) = self.cmd_fsync(path, datasync) {
+                        eprintln!("fsync: {}: {}", path, e);
+                    }
+                }
+
+                "df" => {
+                    let human = args.contains(&"-h");
+                    let path = args
+                        .iter()
+                        .find(|a| !a.starts_with('-'))
+                        .copied()
+                        .unwrap_or("/");
+
+                    if let Err(e) = self.cmd_df(path, human) {
+                        eprintln!("df: {}", e);
+                    }
+                }
+
                 "pwd" => {
                     println!("{}", self.vfs.getcwd().display());
                 }
 
+                "open" => {
+                    // open <path> [r|r+|w|w+|a|a+]: keep an fd open, see lsof
+                    let (path, how) = match args.as_slice() {
+                        [path] => (*path, "r"),
+                        [path, how] => (*path, *how),
+                        _ => {
+                            println!("Usage: open <path> [r|r+|w|w+|a|a+]");
+                            continue;
+                        }
+                    };
+                    match self.cmd_open(path, how) {
+                        Ok(fd) => println!("{}", fd),
+                        Err(e) => eprintln!("open: {}: {}", path, e),
+                    }
+                }
+
+                "close" => {
+                    let Some(Ok(fd)) = args.first().map(|a| a.parse::<Fd>()) else {
+                        println!("Usage: close <fd>");
+                        continue;
+                    };
+                    if let Err(e) = self.vfs.close(fd) {
+                        eprintln!("close: {}: {}", fd, e);
+                    }
+                }
+
+                "lsof" => self.cmd_lsof(),
+
                 "exit" | "quit" => break,
 
                 _ => println!("unknown command: {}", cmd),
             }
         }
-        Ok(())
+
+        self.vfs.shutdown()
     }
 
     /* ---------------------------------------------------------------------
     stat formatting
     --------------------------------------------------------------------- */
-    fn cmd_stat(&self, st: FileStat) -> () {
+    fn cmd_stat(&self, st: FileStat) {
         fn mode_to_string(mode: u32) -> String {
             let mut s = String::new();
             let perms = [
@@ -198,24 +640,28 @@ impl<T: FuseTransport> FuseShell<T> {
     /* ---------------------------------------------------------------------
     ls -l
     --------------------------------------------------------------------- */
-    fn cmd_ls_long(&mut self, path: &str) -> std::io::Result<()> {
-        // Determine if path is file or directory.
-        let st = self.vfs.stat(path)?;
+    /// With `check`, entries the current identity cannot open for reading
+    /// are marked "[no access]".
+    fn cmd_ls_long(&mut self, path: &str, check: bool) -> std::io::Result<()> {
+        // Determine if path is file or directory. A symlink is listed itself
+        // unless the path ends in '/'.
+        let st = self.vfs.lstat(path)?;
 
         if (st.mode & libc::S_IFMT) != libc::S_IFDIR {
             // It's a single file: print one long line.
             let mode_str = Self::format_mode(st.mode);
             let time = Self::format_time(st.mtime);
+            let link = self.link_suffix(path, &st);
 
             println!(
-                "{} {:>2} {:>4} {:>4} {:>8} {} {}",
-                mode_str, st.nlink, st.uid, st.gid, st.size, time, path
+                "{} {:>2} {:>4} {:>4} {:>8} {} {}{}",
+                mode_str, st.nlink, st.uid, st.gid, st.size, time, path, link
             );
             return Ok(());
         }
 
-        // Directory: list entries.
-        let entries = self.vfs.readdir(path)?;
+        // Directory: list entries, with attributes where the server sent them.
+        let entries = self.vfs.readdir_plus(path)?;
 
         for e in entries {
             // Build a path string for stat() that respects ".", "/", and subdirs.
@@ -227,14 +673,25 @@ impl<T: FuseTransport> FuseShell<T> {
                 format!("{}/{}", path.trim_end_matches('/'), e.name)
             };
 
-            match self.vfs.stat(&full_path) {
+            let st = match e.stat {
+                Some(st) => Ok(st),
+                None => self.vfs.lstat(&full_path),
+            };
+
+            match st {
                 Ok(st) => {
                     let mode_str = Self::format_mode(st.mode);
                     let time = Self::format_time(st.mtime);
+                    let link = self.link_suffix(&full_path, &st);
+                    let denied = if check && !self.vfs.can_read(&full_path) {
+                        "  [no access]"
+                    } else {
+                        ""
+                    };
 
                     println!(
-                        "{} {:>2} {:>4} {:>4} {:>8} {} {}",
-                        mode_str, st.nlink, st.uid, st.gid, st.size, time, e.name
+                        "{} {:>2} {:>4} {:>4} {:>8} {} {}{}{}",
+                        mode_str, st.nlink, st.uid, st.gid, st.size, time, e.name, link, denied
                     );
                 }
                 Err(err) => {
@@ -249,6 +706,17 @@ impl<T: FuseTransport> FuseShell<T> {
 
     /* --- helpers for ls -l --- */
 
+    /// " -> target" for symlinks, empty for everything else.
+    fn link_suffix(&mut self, path: &str, st: &FileStat) -> String {
+        if (st.mode & libc::S_IFMT) != libc::S_IFLNK {
+            return String::new();
+        }
+        match self.vfs.readlink(path) {
+            Ok(target) => format!(" -> {}", target),
+            Err(_) => " -> ?".to_string(),
+        }
+    }
+
     fn format_mode(mode: u32) -> String {
         let ftype = match mode & libc::S_IFMT {
             libc::S_IFDIR => 'd',
@@ -298,10 +766,507 @@ impl<T: FuseTransport> FuseShell<T> {
     cat
     --------------------------------------------------------------------- */
     fn cmd_cat(&mut self, path: &str) -> std::io::Result<()> {
-        let fd = self.vfs.open(path, libc::O_RDONLY as u32)?;
-        let data = self.vfs.read(fd, 64 * 1024)?;
-        self.vfs.close(fd)?;
-        print!("{}", String::from_utf8_lossy(&data));
+        let size = self.vfs.stat(path)?.size;
+        let fd = self.vfs.open(path, libc::O_RDONLY as u32, 0)?;
+        let res = self.cat_fd(fd, size);
+        let closed = self.vfs.close(fd);
+        res.and(closed)
+    }
+
+    /// Reads only the data segments; holes are printed as zeros without
+    /// asking the server for them.
+    fn cat_fd(&mut self, fd: Fd, size: u64) -> std::io::Result<()> {
+        const CHUNK: u64 = 64 * 1024;
+
+        let segments: Vec<_> = self.vfs.data_segments(fd).collect::<io::Result<_>>()?;
+        let mut out = io::stdout().lock();
+        let mut pos = 0;
+
+        for seg in segments.iter().cloned().chain(std::iter::once(size..size)) {
+            Self::write_zeros(&mut out, seg.start.saturating_sub(pos))?;
+            pos = pos.max(seg.start);
+
+            self.vfs.seek(fd, SeekFrom::Start(pos))?;
+            while pos < seg.end {
+                let data = self.vfs.read(fd, (seg.end - pos).min(CHUNK) as u32)?;
+                if data.is_empty() {
+                    break; // truncated underneath us
+                }
+                out.write_all(&data)?;
+                pos += data.len() as u64;
+            }
+        }
+
+        out.flush()
+    }
+
+    fn write_zeros(out: &mut impl Write, mut len: u64) -> io::Result<()> {
+        let zeros = [0u8; 4096];
+        while len > 0 {
+            let n = len.min(zeros.len() as u64) as usize;
+            out.write_all(&zeros[..n])?;
+            len -= n as u64;
+        }
+        Ok(())
+    }
+
+    /* ---------------------------------------------------------------------
+    write / append
+    --------------------------------------------------------------------- */
+    fn cmd_write(&mut self, path: &str, data: &[u8], append: bool) -> std::io::Result<()> {
+        let flags = if append {
+            libc::O_WRONLY | libc::O_CREAT | libc::O_APPEND
+        } else {
+            libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC
+        };
+
+        let fd = self.vfs.open(path, flags as u32, 0o644)?;
+        let res = self.vfs.write(fd, data);
+        let closed = self.vfs.close(fd);
+
+        // A failed WRITE matters more than a failed close after it.
+        let written = res?;
+        closed?;
+        if written < data.len() {
+            eprintln!("short write: {} of {} bytes", written, data.len());
+        }
+        Ok(())
+    }
+
+    /* ---------------------------------------------------------------------
+    touch
+    --------------------------------------------------------------------- */
+    fn cmd_touch(&mut self, path: &str, when: Option<(u64, u32)>) -> std::io::Result<()> {
+        // Missing files are created; existing ones only get new timestamps.
+        match self.vfs.stat(path) {
+            Ok(_) => {}
+            Err(e) if errno_of(&e) == Some(libc::ENOENT) => {
+                let flags = libc::O_WRONLY | libc::O_CREAT;
+                let fd = self.vfs.open(path, flags as u32, 0o644)?;
+                self.vfs.close(fd)?;
+            }
+            Err(e) => return Err(e),
+        }
+
+        let time = |w: Option<(u64, u32)>| match w {
+            Some((sec, nsec)) => SetTime::At { sec, nsec },
+            None => SetTime::Now,
+        };
+        self.vfs
+            .set_times(path, Some(time(when)), Some(time(when)))?;
+        Ok(())
+    }
+
+    /// "@secs", "YYYY-MM-DD" or "YYYY-MM-DDTHH:MM:SS" in local time.
+    fn parse_date(s: &str) -> Option<(u64, u32)> {
+        use chrono::{Local, NaiveDate, NaiveDateTime, TimeZone};
+
+        if let Some(secs) = s.strip_prefix('@') {
+            return secs.parse().ok().map(|s| (s, 0));
+        }
+
+        let naive = NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M:%S")
+            .ok()
+            .or_else(|| {
+                NaiveDate::parse_from_str(s, "%Y-%m-%d")
+                    .ok()
+                    .and_then(|d| d.and_hms_opt(0, 0, 0))
+            })?;
+
+        let dt = Local.from_local_datetime(&naive).earliest()?;
+        Some((dt.timestamp().max(0) as u64, dt.timestamp_subsec_nanos()))
+    }
+
+    /// Byte count with an optional K/M/G (powers of 1024) suffix.
+    fn parse_size(s: &str) -> Option<u64> {
+        let (num, mult) = match s.chars().last()? {
+            'K' | 'k' => (&s[..s.len() - 1], 1 << 10),
+            'M' | 'm' => (&s[..s.len() - 1], 1 << 20),
+            'G' | 'g' => (&s[..s.len() - 1], 1 << 30),
+            _ => (s, 1),
+        };
+        num.parse::<u64>().ok()?.checked_mul(mult)
+    }
+
+    /* ---------------------------------------------------------------------
+    rm -r: directories are removed recursively, anything else is unlinked.
+    Every entry that cannot be removed is reported, not just the first.
+    --------------------------------------------------------------------- */
+    fn cmd_rm_recursive(&mut self, path: &str) {
+        // Failures were reported as they happened.
+        let _ = self
+            .vfs
+            .remove_dir_all_with(path, &mut |p, e| eprintln!("rm: {}: {}", p, e));
+    }
+
+    /* ---------------------------------------------------------------------
+    mv: an existing directory as destination receives the sources by name
+    --------------------------------------------------------------------- */
+    fn cmd_mv(&mut self, srcs: &[&str], dst: &str, flags: u32) {
+        let dst_is_dir = flags & RENAME_EXCHANGE == 0
+            && matches!(self.vfs.stat(dst), Ok(st) if (st.mode & libc::S_IFMT) == libc::S_IFDIR);
+
+        if srcs.len() > 1 && !dst_is_dir {
+            eprintln!("mv: target '{}' is not a directory", dst);
+            return;
+        }
+
+        for src in srcs {
+            let target = if dst_is_dir {
+                let name = src.trim_end_matches('/').rsplit('/').next().unwrap_or(src);
+                format!("{}/{}", dst.trim_end_matches('/'), name)
+            } else {
+                dst.to_string()
+            };
+
+            if let Err(e) = self.vfs.rename(src, &target, flags) {
+                eprintln!("mv: {} -> {}: {}", src, target, e);
+            }
+        }
+    }
+
+    /* ---------------------------------------------------------------------
+    cp: same target rules as mv; modes and timestamps are carried over
+    --------------------------------------------------------------------- */
+    fn cmd_cp(&mut self, srcs: &[&str], dst: &str, recursive: bool) {
+        let dst_is_dir =
+            matches!(self.vfs.stat(dst), Ok(st) if (st.mode & libc::S_IFMT) == libc::S_IFDIR);
+
+        if srcs.len() > 1 && !dst_is_dir {
+            eprintln!("cp: target '{}' is not a directory", dst);
+            return;
+        }
+
+        for src in srcs {
+            let target = if dst_is_dir {
+                let name = src.trim_end_matches('/').rsplit('/').next().unwrap_or(src);
+                format!("{}/{}", dst.trim_end_matches('/'), name)
+            } else {
+                dst.to_string()
+            };
+
+            if let Err(e) = self.cp_one(src, &target, recursive) {
+                eprintln!("cp: {} -> {}: {}", src, target, e);
+            }
+        }
+    }
+
+    fn cp_one(&mut self, src: &str, dst: &str, recursive: bool) -> std::io::Result<()> {
+        let st = self.vfs.lstat(src)?;
+
+        match st.mode & libc::S_IFMT {
+            libc::S_IFDIR => {
+                if !recursive {
+                    return Err(io::Error::other("-r not specified; omitting directory"));
+                }
+
+                // List before creating dst, so copying a directory into
+                // itself does not pick up the copy.
+                let entries = self.vfs.readdir(src)?;
+                // Owner rwx until the contents are in, so a read-only source
+                // directory can still be filled; set_permissions below puts
+                // the real mode back.
+                if let Err(e) = self.vfs.mkdir(dst, (st.mode & 0o7777) | 0o700) {
+                    // An existing directory is copied into, like cp -r does.
+                    let is_dir = matches!(self.vfs.stat(dst),
+                        Ok(d) if (d.mode & libc::S_IFMT) == libc::S_IFDIR);
+                    if !is_dir {
+                        return Err(e);
+                    }
+                }
+
+                for e in entries {
+                    if e.name == "." || e.name == ".." {
+                        continue;
+                    }
+                    let from = format!("{}/{}", src.trim_end_matches('/'), e.name);
+                    let to = format!("{}/{}", dst.trim_end_matches('/'), e.name);
+                    match self.cp_one(&from, &to, true) {
+                        // Ctrl-C stops the whole tree, not just this entry.
+                        Err(err) if err.kind() == io::ErrorKind::Interrupted => return Err(err),
+                        Err(err) => eprintln!("cp: {} -> {}: {}", from, to, err),
+                        Ok(()) => {}
+                    }
+                }
+            }
+            libc::S_IFLNK => {
+                // Links are recreated, not followed; their times can't be set.
+                let target = self.vfs.readlink(src)?;
+                return self.vfs.symlink(&target, dst);
+            }
+            libc::S_IFREG => {
+                self.vfs.copy(src, dst)?;
+            }
+            _ => {
+                self.vfs.mknod(dst, st.mode, st.rdev)?;
+            }
+        }
+
+        // After the contents, so writing them does not bump mtime again.
+        self.vfs.set_permissions(dst, st.mode)?;
+        let atime = SetTime::At {
+            sec: st.atime,
+            nsec: st.atime_nsec,
+        };
+        let mtime = SetTime::At {
+            sec: st.mtime,
+            nsec: st.mtime_nsec,
+        };
+        self.vfs.set_times(dst, Some(atime), Some(mtime))?;
+        Ok(())
+    }
+
+    /* ---------------------------------------------------------------------
+    open: flags as fopen(3) spells them; the fd stays open until close
+    --------------------------------------------------------------------- */
+    fn cmd_open(&mut self, path: &str, how: &str) -> io::Result<Fd> {
+        let (access, extra) = match how.trim_end_matches('+') {
+            "r" => (libc::O_RDONLY, 0),
+            "w" => (libc::O_WRONLY, libc::O_CREAT | libc::O_TRUNC),
+            "a" => (libc::O_WRONLY, libc::O_CREAT | libc::O_APPEND),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("invalid mode: {}", how),
+                ));
+            }
+        };
+        let access = if how.ends_with('+') {
+            libc::O_RDWR
+        } else {
+            access
+        };
+
+        self.vfs.open(path, (access | extra) as u32, 0o644)
+    }
+
+    /* ---------------------------------------------------------------------
+    lsof: the fd table this shell holds open on the server
+    --------------------------------------------------------------------- */
+    fn cmd_lsof(&self) {
+        println!(
+            "{:>4} {:>8} {:>8} {:>10} {:<4} PATH",
+            "FD", "INODE", "FH", "OFFSET", "MODE"
+        );
+        for (fd, of) in self.vfs.open_files() {
+            let mode = match of.flags & libc::O_ACCMODE as u32 {
+                f if f == libc::O_WRONLY as u32 => "w",
+                f if f == libc::O_RDWR as u32 => "rw",
+                _ => "r",
+            };
+            let append = if of.flags & libc::O_APPEND as u32 != 0 {
+                "a"
+            } else {
+                ""
+            };
+            println!(
+                "{:>4} {:>8} {:>8} {:>10} {:<4} {}",
+                fd,
+                of.inode,
+                of.fh,
+                of.offset,
+                format!("{}{}", mode, append),
+                of.path
+            );
+        }
+    }
+
+    /* ---------------------------------------------------------------------
+    df: sizes use frsize, the unit f_blocks is counted in
+    --------------------------------------------------------------------- */
+    fn cmd_df(&mut self, path: &str, human: bool) -> std::io::Result<()> {
+        let st = self.vfs.statfs(path)?;
+
+        let unit = if st.frsize != 0 { st.frsize } else { st.bsize } as u64;
+        let total = st.blocks * unit;
+        let used = st.blocks.saturating_sub(st.bfree) * unit;
+        let avail = st.bavail * unit;
+
+        // Like df: Use% is used / (used + available), rounded up.
+        let usable = used + avail;
+        let pct = if usable == 0 {
+            0
+        } else {
+            (used * 100).div_ceil(usable)
+        };
+
+        let fmt = |bytes: u64| {
+            if human {
+                Self::format_human(bytes)
+            } else {
+                (bytes / 1024).to_string()
+            }
+        };
+
+        println!(
+            "{:<12} {:>10} {:>10} {:>10} {:>5} {:>10} {:>10}",
+            "Filesystem",
+            if human { "Size" } else { "1K-blocks" },
+            "Used",
+            "Available",
+            "Use%",
+            "Inodes",
+            "IFree"
+        );
+        println!(
+            "{:<12} {:>10} {:>10} {:>10} {:>4}% {:>10} {:>10}",
+            "virtiofs",
+            fmt(total),
+            fmt(used),
+            fmt(avail),
+            pct,
+            st.files,
+            st.ffree
+        );
         Ok(())
     }
+
+    /// 1536 -> "1.5K", powers of 1024 like df -h.
+    fn format_human(bytes: u64) -> String {
+        const UNITS: [&str; 6] = ["B", "K", "M", "G", "T", "P"];
+
+        let mut value = bytes as f64;
+        let mut unit = 0;
+        while value >= 1024.0 && unit < UNITS.len() - 1 {
+            value /= 1024.0;
+            unit += 1;
+        }
+
+        if unit == 0 {
+            format!("{}{}", bytes, UNITS[0])
+        } else if value < 10.0 {
+            format!("{:.1}{}", value, UNITS[unit])
+        } else {
+            format!("{:.0}{}", value, UNITS[unit])
+        }
+    }
+
+    /* ---------------------------------------------------------------------
+    extended attributes
+    --------------------------------------------------------------------- */
+    fn cmd_getfattr(&mut self, path: &str, name: Option<&str>) -> std::io::Result<()> {
+        let names = match name {
+            Some(n) => vec![n.to_string()],
+            None => self.vfs.list_xattr(path)?,
+        };
+
+        println!("# file: {}", path);
+        for n in names {
+            let value = self.vfs.get_xattr(path, &n)?;
+            println!("{}={}", n, Self::format_xattr_value(&value));
+        }
+        Ok(())
+    }
+
+    /// setfattr -n <name> [-v <value>] [--create|--replace] <path>
+    /// setfattr -x <name> <path>
+    fn cmd_setfattr(&mut self, args: &[&str]) -> std::io::Result<()> {
+        let mut name = None;
+        let mut value = "";
+        let mut remove = None;
+        let mut flags = 0;
+        let mut path = None;
+
+        let mut it = args.iter();
+        while let Some(a) = it.next() {
+            match *a {
+                "-n" => name = it.next().copied(),
+                "-v" => value = it.next().copied().unwrap_or(""),
+                "-x" => remove = it.next().copied(),
+                "--create" => flags |= XATTR_CREATE,
+                "--replace" => flags |= XATTR_REPLACE,
+                p => path = Some(p),
+            }
+        }
+
+        match (name, remove, path) {
+            (Some(name), None, Some(path)) => {
+                self.vfs.set_xattr(path, name, value.as_bytes(), flags)
+            }
+            (None, Some(name), Some(path)) => self.vfs.remove_xattr(path, name),
+            _ => {
+                println!("Usage: setfattr -n <name> [-v <value>] [--create|--replace] <path>");
+                println!("       setfattr -x <name> <path>");
+                Ok(())
+            }
+        }
+    }
+
+    /// Quoted text when the value is printable, 0x-prefixed hex otherwise.
+    fn format_xattr_value(value: &[u8]) -> String {
+        match std::str::from_utf8(value) {
+            Ok(s) if s.chars().all(|c| !c.is_control()) => format!("\"{}\"", s),
+            _ => {
+                let hex: String = value.iter().map(|b| format!("{:02x}", b)).collect();
+                format!("0x{}", hex)
+            }
+        }
+    }
+
+    /* ---------------------------------------------------------------------
+    fallocate: plain preallocation creates the file, like util-linux
+    --------------------------------------------------------------------- */
+    fn cmd_fallocate(&mut self, path: &str, offset: u64, len: u64, mode: u32) -> io::Result<()> {
+        let mut flags = libc::O_WRONLY as u32;
+        if mode & (FALLOC_FL_PUNCH_HOLE | FALLOC_FL_ZERO_RANGE) == 0 {
+            flags |= libc::O_CREAT as u32;
+        }
+
+        let fd = self.vfs.open(path, flags, 0o644)?;
+        let allocated = if mode == FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE {
+            self.vfs.punch_hole(fd, offset, len)
+        } else {
+            self.vfs.allocate(fd, offset, len, mode)
+        };
+        let closed = self.vfs.close(fd);
+        allocated.and(closed)
+    }
+
+    /* ---------------------------------------------------------------------
+    poll: blocks on server wakeups until the file is readable
+    --------------------------------------------------------------------- */
+    fn cmd_poll(&mut self, path: &str, timeout: Option<Duration>) -> io::Result<()> {
+        let fd = self.vfs.open(path, libc::O_RDONLY as u32, 0)?;
+        let polled = self.vfs.poll(fd, libc::POLLIN as u32, timeout);
+        let closed = self.vfs.close(fd);
+        let revents = polled?;
+        closed?;
+
+        if revents == 0 {
+            println!("timeout");
+            return Ok(());
+        }
+
+        let names = [
+            (libc::POLLIN, "POLLIN"),
+            (libc::POLLPRI, "POLLPRI"),
+            (libc::POLLOUT, "POLLOUT"),
+            (libc::POLLERR, "POLLERR"),
+            (libc::POLLHUP, "POLLHUP"),
+            (libc::POLLNVAL, "POLLNVAL"),
+        ];
+        let ready: Vec<&str> = names
+            .iter()
+            .filter(|(bit, _)| revents & *bit as u32 != 0)
+            .map(|(_, name)| *name)
+            .collect();
+        println!("{}", ready.join(" "));
+        Ok(())
+    }
+
+    /* ---------------------------------------------------------------------
+    fsync: directories go through FSYNCDIR, everything else through FSYNC
+    --------------------------------------------------------------------- */
+    fn cmd_fsync(&mut self, path: &str, datasync: bool) -> std::io::Result<()> {
+        let st = self.vfs.stat(path)?;
+
+        if (st.mode & libc::S_IFMT) == libc::S_IFDIR {
+            return self.vfs.fsync_dir(path, datasync);
+        }
+
+        let fd = self.vfs.open(path, libc::O_RDONLY as u32, 0)?;
+        let synced = self.vfs.fsync(fd, datasync);
+        let closed = self.vfs.close(fd);
+        synced.and(closed)
+    }
 }
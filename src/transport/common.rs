@@ -1,4 +1,5 @@
 use std::io;
+use std::time::Duration;
 
 pub trait FuseTransport {
     /// Send a complete FUSE request and receive a complete FUSE reply.
@@ -8,5 +9,34 @@ pub trait FuseTransport {
     ///   [4..]   = header + body
     ///
     /// The returned Vec MUST contain the entire reply in the same format.
+    /// Server notifications (unique 0) cannot be told apart here, so
+    /// transports that may see them must also implement `recv`.
     fn roundtrip(&mut self, req: &[u8]) -> io::Result<Vec<u8>>;
+
+    /// Send a complete FUSE request without waiting for anything back.
+    /// Transports that cannot fail with `ErrorKind::Unsupported`; FORGETs
+    /// are then skipped.
+    fn send(&mut self, _req: &[u8]) -> io::Result<()> {
+        Err(io::Error::from(io::ErrorKind::Unsupported))
+    }
+
+    /// Largest reply the transport can take, fuse_out_header included.
+    /// `None` if it has no limit.
+    fn max_message(&self) -> Option<usize> {
+        None
+    }
+
+    /// Whether replies can be read on their own with `recv`. Transports that
+    /// only get a reply back together with its request use `roundtrip`, and
+    /// requests on them have no deadline.
+    fn supports_recv(&self) -> bool {
+        false
+    }
+
+    /// Receive the next complete message from the server, waiting at most
+    /// `timeout` (forever if `None`). `Ok(None)` means nothing arrived in
+    /// time, or a signal cut the wait short.
+    fn recv(&mut self, _timeout: Option<Duration>) -> io::Result<Option<Vec<u8>>> {
+        Err(io::Error::from(io::ErrorKind::Unsupported))
+    }
 }
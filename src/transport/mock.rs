@@ -0,0 +1,95 @@
+//! In-memory server for unit tests.
+
+use std::cell::RefCell;
+use std::io;
+use std::rc::Rc;
+
+use crate::transport::common::FuseTransport;
+
+const IN_HEADER_SIZE: usize = 40;
+
+/// A request as the mock server saw it.
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub opcode: u32,
+    pub unique: u64,
+    pub nodeid: u64,
+    pub body: Vec<u8>,
+}
+
+type Handler = Box<dyn FnMut(&Request) -> Result<Vec<u8>, i32>>;
+
+/// Answers every request with the payload `handler` returns for it, or with
+/// its errno. Everything sent is logged in `sent`.
+pub struct MockTransport {
+    pub sent: Rc<RefCell<Vec<Request>>>,
+    handler: Handler,
+    /// Whether `send` works; without it the transport behaves like one that
+    /// only has `roundtrip`.
+    one_way: bool,
+    max_message: Option<usize>,
+}
+
+impl MockTransport {
+    pub fn new(handler: impl FnMut(&Request) -> Result<Vec<u8>, i32> + 'static) -> Self {
+        Self {
+            sent: Rc::default(),
+            handler: Box::new(handler),
+            one_way: true,
+            max_message: None,
+        }
+    }
+
+    /// A transport whose replies can be at most `max` bytes.
+    pub fn max_message(mut self, max: usize) -> Self {
+        self.max_message = Some(max);
+        self
+    }
+
+    /// A transport whose `send` fails with `ErrorKind::Unsupported`.
+    pub fn roundtrip_only(mut self) -> Self {
+        self.one_way = false;
+        self
+    }
+
+    fn record(&mut self, req: &[u8]) -> Request {
+        let u64_at = |at: usize| u64::from_le_bytes(req[at..at + 8].try_into().unwrap());
+        let request = Request {
+            opcode: u32::from_le_bytes(req[4..8].try_into().unwrap()),
+            unique: u64_at(8),
+            nodeid: u64_at(16),
+            body: req[IN_HEADER_SIZE..].to_vec(),
+        };
+        self.sent.borrow_mut().push(request.clone());
+        request
+    }
+}
+
+impl FuseTransport for MockTransport {
+    fn roundtrip(&mut self, req: &[u8]) -> io::Result<Vec<u8>> {
+        let request = self.record(req);
+        let (error, payload) = match (self.handler)(&request) {
+            Ok(payload) => (0, payload),
+            Err(errno) => (-errno, Vec::new()),
+        };
+
+        let mut reply = Vec::with_capacity(16 + payload.len());
+        reply.extend_from_slice(&(16 + payload.len() as u32).to_le_bytes());
+        reply.extend_from_slice(&error.to_le_bytes());
+        reply.extend_from_slice(&request.unique.to_le_bytes());
+        reply.extend_from_slice(&payload);
+        Ok(reply)
+    }
+
+    fn send(&mut self, req: &[u8]) -> io::Result<()> {
+        if !self.one_way {
+            return Err(io::Error::from(io::ErrorKind::Unsupported));
+        }
+        self.record(req);
+        Ok(())
+    }
+
+    fn max_message(&self) -> Option<usize> {
+        self.max_message
+    }
+}
@@ -1,4 +1,6 @@
 pub mod common;
+#[cfg(test)]
+pub mod mock;
 pub mod unix_socket;
 
 #[cfg(all(target_os = "redox", feature = "virtio-fs"))]
@@ -1,5 +1,7 @@
 use std::io;
+use std::os::fd::AsRawFd;
 use std::os::unix::net::{UnixListener, UnixStream};
+use std::time::Duration;
 use std::{
     fs,
     io::{Read, Write},
@@ -58,4 +60,40 @@ impl FuseTransport for FuseStream {
         self.send(req)?;
         self.recv_raw()
     }
+
+    fn send(&mut self, req: &[u8]) -> io::Result<()> {
+        FuseStream::send(self, req)
+    }
+
+    fn supports_recv(&self) -> bool {
+        true
+    }
+
+    fn recv(&mut self, timeout: Option<Duration>) -> io::Result<Option<Vec<u8>>> {
+        let ms = match timeout {
+            Some(t) => t.as_micros().div_ceil(1000).min(i32::MAX as u128) as i32,
+            None => -1,
+        };
+        let mut pfd = libc::pollfd {
+            fd: self.stream.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+
+        let n = unsafe { libc::poll(&mut pfd, 1, ms) };
+        if n < 0 {
+            let err = io::Error::last_os_error();
+            // A signal (Ctrl-C) woke us up; the caller decides what it means.
+            if err.kind() == io::ErrorKind::Interrupted {
+                return Ok(None);
+            }
+            return Err(err);
+        }
+        if n == 0 {
+            return Ok(None);
+        }
+
+        // Readable (or hung up, which recv_raw reports as EOF).
+        self.recv_raw().map(Some)
+    }
 }
@@ -75,8 +75,34 @@ impl<'a> FuseTransport for VirtioFsTransport<'a> {
 
         Ok(resp_buf[..len_field].to_vec())
     }
+
+    fn max_message(&self) -> Option<usize> {
+        Some(MAX_FUSE_MSG)
+    }
+
+    fn send(&mut self, req: &[u8]) -> io::Result<()> {
+        // TODO: FORGETs belong on the hiprio queue per the virtio-fs spec.
+        // Until that queue is wired up they share the request queue, where
+        // they wait behind regular requests. The chain has no
+        // device-writable part since there is no reply.
+        let req_len = req.len();
+
+        let mut req_dma = unsafe {
+            Dma::<[u8]>::zeroed_slice(req_len)
+                .map_err(to_io_err)?
+                .assume_init()
+        };
+        req_dma[..req_len].copy_from_slice(req);
+
+        let chain = ChainBuilder::new()
+            .chain(Buffer::new_sized(&req_dma, req_len))
+            .build();
+
+        futures::executor::block_on(self.queue.send(chain));
+        Ok(())
+    }
 }
 
 fn to_io_err<E: core::fmt::Debug>(e: E) -> io::Error {
-    io::Error::new(io::ErrorKind::Other, format!("{e:?}"))
+    io::Error::other(format!("{e:?}"))
 }
@@ -0,0 +1,98 @@
+//! Errors carrying the errno from a FUSE reply.
+
+use std::fmt;
+
+/// A non-zero `error` field from a fuse_out_header, stored as a positive errno.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FuseError {
+    pub errno: i32,
+}
+
+impl FuseError {
+    pub fn new(errno: i32) -> Self {
+        Self { errno }
+    }
+
+    /// Wraps the errno in an `io::Error` whose kind matches the OS mapping.
+    pub fn into_io(self) -> std::io::Error {
+        let kind = std::io::Error::from_raw_os_error(self.errno).kind();
+        std::io::Error::new(kind, self)
+    }
+}
+
+impl fmt::Display for FuseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", errno_name(self.errno), self.errno)
+    }
+}
+
+impl std::error::Error for FuseError {}
+
+/// Returns the errno behind `err`, whether it came from a FUSE reply or the OS.
+pub fn errno_of(err: &std::io::Error) -> Option<i32> {
+    if let Some(fe) = err.get_ref().and_then(|e| e.downcast_ref::<FuseError>()) {
+        return Some(fe.errno);
+    }
+    err.raw_os_error()
+}
+
+pub fn errno_name(code: i32) -> &'static str {
+    match code {
+        libc::ENOENT => "ENOENT (No such file or directory)",
+        libc::EACCES => "EACCES (Permission denied)",
+        libc::EEXIST => "EEXIST (File exists)",
+        libc::ENOSYS => "ENOSYS (Function not implemented)",
+        libc::EROFS => "EROFS (Read-only filesystem)",
+        libc::ENOTDIR => "ENOTDIR (Not a directory)",
+        libc::EISDIR => "EISDIR (Is a directory)",
+        libc::EINVAL => "EINVAL (Invalid argument)",
+        libc::EPERM => "EPERM (Operation not permitted)",
+        libc::ENOTEMPTY => "ENOTEMPTY (Directory not empty)",
+        libc::EXDEV => "EXDEV (Invalid cross-device link)",
+        libc::EBUSY => "EBUSY (Device or resource busy)",
+        libc::EIO => "EIO (Input/output error)",
+        libc::EMLINK => "EMLINK (Too many links)",
+        libc::ENODATA => "ENODATA (No data available)",
+        libc::ERANGE => "ERANGE (Numerical result out of range)",
+        libc::EOPNOTSUPP => "EOPNOTSUPP (Operation not supported)",
+        libc::EAGAIN => "EAGAIN (Resource temporarily unavailable)",
+        libc::EDEADLK => "EDEADLK (Resource deadlock avoided)",
+        libc::ENOLCK => "ENOLCK (No locks available)",
+        libc::ELOOP => "ELOOP (Too many levels of symbolic links)",
+        libc::EBADF => "EBADF (Bad file descriptor)",
+        libc::ENOSPC => "ENOSPC (No space left on device)",
+        libc::EFBIG => "EFBIG (File too large)",
+        libc::ENXIO => "ENXIO (No such device or address)",
+        libc::EINTR => "EINTR (Interrupted system call)",
+        libc::ETIMEDOUT => "ETIMEDOUT (Connection timed out)",
+        libc::ENOTTY => "ENOTTY (Inappropriate ioctl for device)",
+        libc::EFAULT => "EFAULT (Bad address)",
+        libc::ENOTCONN => "ENOTCONN (Transport endpoint is not connected)",
+        _ => "Unknown error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn errno_of_fuse_error() {
+        let err = FuseError::new(libc::ENOTEMPTY).into_io();
+        assert_eq!(errno_of(&err), Some(libc::ENOTEMPTY));
+        assert_eq!(err.kind(), std::io::ErrorKind::DirectoryNotEmpty);
+    }
+
+    #[test]
+    fn errno_of_os_error() {
+        let err = std::io::Error::from_raw_os_error(libc::EACCES);
+        assert_eq!(errno_of(&err), Some(libc::EACCES));
+    }
+
+    #[test]
+    fn errno_of_other_error() {
+        assert_eq!(errno_of(&std::io::Error::other("bad fd")), None);
+        let err = std::io::Error::new(std::io::ErrorKind::NotFound, "no errno");
+        assert_eq!(errno_of(&err), None);
+    }
+}
@@ -1,11 +1,46 @@
 pub mod structs;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::path::PathBuf;
 
-use self::structs::{DirEntryInfo, Fd, FileStat, OpenFile};
-use crate::protocol::FuseProtocol;
+use self::structs::{
+    DirEntryInfo, Fd, FileStat, FsStat, LockInfo, LockKind, OpenFile, SeekFrom, SetTime,
+    SubscriptionId,
+};
+use crate::protocol::{
+    FALLOC_FL_KEEP_SIZE, FALLOC_FL_PUNCH_HOLE, FATTR_ATIME, FATTR_ATIME_NOW, FATTR_FH, FATTR_GID,
+    FATTR_MODE, FATTR_MTIME, FATTR_MTIME_NOW, FATTR_SIZE, FATTR_UID, FUSE_IOCTL_UNRESTRICTED,
+    FUSE_POLL_SCHEDULE_NOTIFY, FUSE_RELEASE_FLOCK_UNLOCK, FuseAttr, FuseCopyFileRangeIn,
+    FuseFileLock, FuseInitFlags, FuseNotification, FuseProtocol, FuseSetattrIn, ioc_size,
+};
 use crate::transport::common::FuseTransport;
+use crate::util::error::{FuseError, errno_of};
+
+/// Symlinks followed while resolving a single path before giving up with
+/// ELOOP; the same limit Linux uses (MAXSYMLINKS).
+const MAX_SYMLINK_HOPS: u32 = 40;
+
+/// Largest offset a lock can carry (the kernel's OFFSET_MAX); a lock that
+/// ends here extends to the end of the file, however large it grows.
+const OFFSET_MAX: u64 = i64::MAX as u64;
+
+/// Largest COPY_FILE_RANGE request, the same page-aligned cap the kernel uses.
+const COPY_RANGE_MAX: u64 = u32::MAX as u64 & !0xfff;
+
+/// Chunk size of the read/write loop when the server cannot copy itself.
+const COPY_CHUNK: u64 = 128 * 1024;
+
+/// What poll reports for files of a server without POLL (the kernel's
+/// DEFAULT_POLLMASK).
+const DEFAULT_POLLMASK: u32 =
+    (libc::POLLIN | libc::POLLOUT | libc::POLLRDNORM | libc::POLLWRNORM) as u32;
+
+/// Inodes used since the last `forget_unused` that it keeps references on;
+/// past this, those go too.
+const LOOKUP_CACHE_MAX: usize = 256;
+
+/// A `subscribe` callback.
+type Subscriber = Box<dyn FnMut(&FuseNotification)>;
 
 pub struct VirtioFsImpl<T: FuseTransport> {
     proto: FuseProtocol<T>,
@@ -13,6 +48,27 @@ pub struct VirtioFsImpl<T: FuseTransport> {
     cwd_path: PathBuf,
     next_fd: Fd,
     open_files: HashMap<Fd, OpenFile>,
+    umask: u32,
+    /// Server answered ACCESS with ENOSYS; check permissions locally from now on.
+    no_access: bool,
+    /// Server answered LSEEK with ENOSYS; treat every file as fully allocated.
+    no_lseek: bool,
+    /// Server answered COPY_FILE_RANGE with ENOSYS; copy through the client.
+    no_copy_range: bool,
+    /// Server answered POLL with ENOSYS; files are always ready.
+    no_poll: bool,
+    /// Server answered SYNCFS with ENOSYS; sync the root directory instead.
+    no_syncfs: bool,
+    /// Lookup references we hold on the server, per nodeid. Every reply that
+    /// carries a fuse_entry_out adds one; FORGET gives them back.
+    lookups: HashMap<u64, u64>,
+    /// Inodes looked up since the last `forget_unused`.
+    recent: HashSet<u64>,
+    /// `shutdown` already ran; the session is gone.
+    shut_down: bool,
+    /// Callbacks run by `process_notifications`, in subscription order.
+    subscribers: Vec<(SubscriptionId, Subscriber)>,
+    next_subscription: SubscriptionId,
 }
 
 impl<T: FuseTransport> VirtioFsImpl<T> {
@@ -23,70 +79,229 @@ impl<T: FuseTransport> VirtioFsImpl<T> {
             cwd_path: PathBuf::from("/"),
             next_fd: 3, // 0,1,2 reserved in spirit
             open_files: HashMap::new(),
+            umask: 0o022,
+            no_access: false,
+            no_lseek: false,
+            no_copy_range: false,
+            no_poll: false,
+            no_syncfs: false,
+            lookups: HashMap::new(),
+            recent: HashSet::new(),
+            shut_down: false,
+            subscribers: Vec::new(),
+            next_subscription: 1,
         }
     }
 
+    /// Deadline for each request to the server; see `FuseProtocol::set_timeout`.
+    pub fn set_timeout(&mut self, timeout: Option<std::time::Duration>) {
+        self.proto.set_timeout(timeout);
+    }
+
+    /// Lets `flag` interrupt the running operation; see
+    /// `FuseProtocol::set_cancel_flag`.
+    pub fn set_cancel_flag(&mut self, flag: &'static std::sync::atomic::AtomicBool) {
+        self.proto.set_cancel_flag(flag);
+    }
+
+    /// Sets the file mode creation mask and returns the previous one, like umask(2).
+    pub fn umask(&mut self, mask: u32) -> u32 {
+        std::mem::replace(&mut self.umask, mask & 0o777)
+    }
+
     pub fn getcwd(&self) -> &std::path::Path {
         &self.cwd_path
     }
 
+    /// Counts a lookup reference the server just handed out for `nodeid`.
+    /// The root is never forgotten, and 0 means no inode.
+    fn remember(&mut self, nodeid: u64) {
+        if nodeid > 1 {
+            *self.lookups.entry(nodeid).or_insert(0) += 1;
+            self.recent.insert(nodeid);
+        }
+    }
+
+    /// Sends FORGET for every inode not looked up since the previous call,
+    /// except the cwd and open files. Inodes the last operation used are kept
+    /// for the next one, up to LOOKUP_CACHE_MAX of them. Call it between
+    /// operations; nothing but paths and fds is kept across calls.
+    pub fn forget_unused(&mut self) -> std::io::Result<()> {
+        let mut pinned: HashSet<u64> = self.open_files.values().map(|of| of.inode).collect();
+        pinned.insert(self.cwd_inode);
+
+        let recent = std::mem::take(&mut self.recent);
+        let keep_recent = recent.len() <= LOOKUP_CACHE_MAX;
+
+        let mut victims = Vec::new();
+        self.lookups.retain(|&nodeid, &mut nlookup| {
+            if pinned.contains(&nodeid) || (keep_recent && recent.contains(&nodeid)) {
+                return true;
+            }
+            victims.push((nodeid, nlookup));
+            false
+        });
+
+        self.proto.batch_forget(&victims)
+    }
+
+    /// Calls `callback` for each notification the server sends from now on
+    /// (invalidations, deletes, stores, retrieves), as `process_notifications`
+    /// picks them up.
+    pub fn subscribe(
+        &mut self,
+        callback: impl FnMut(&FuseNotification) + 'static,
+    ) -> SubscriptionId {
+        let id = self.next_subscription;
+        self.next_subscription += 1;
+        self.subscribers.push((id, Box::new(callback)));
+        id
+    }
+
+    /// Returns false if `id` was not subscribed.
+    pub fn unsubscribe(&mut self, id: SubscriptionId) -> bool {
+        let before = self.subscribers.len();
+        self.subscribers.retain(|(sub, _)| *sub != id);
+        self.subscribers.len() != before
+    }
+
+    /// Applies the notifications received since the last call and hands
+    /// them to the subscribers. Notifications also arrive while waiting for
+    /// replies, so call it between operations, like `forget_unused`.
+    /// Returns how many there were.
+    pub fn process_notifications(&mut self) -> std::io::Result<usize> {
+        let notifications = self.proto.take_notifications()?;
+
+        for n in &notifications {
+            self.apply_notification(n);
+            for (_, callback) in &mut self.subscribers {
+                callback(n);
+            }
+        }
+
+        Ok(notifications.len())
+    }
+
+    /// We cache no attributes, entries or data, so only the paths of open
+    /// files can go stale: a file unlinked under the name it was opened by
+    /// is shown as "(deleted)", like /proc/<pid>/fd does.
+    fn apply_notification(&mut self, n: &FuseNotification) {
+        let FuseNotification::Delete {
+            parent,
+            child,
+            name,
+        } = n
+        else {
+            return;
+        };
+
+        for of in self.open_files.values_mut() {
+            let opened_as =
+                of.parent == *parent && of.path.rsplit('/').next() == Some(name.as_str());
+            if of.inode == *child && opened_as {
+                of.path.push_str(" (deleted)");
+            }
+        }
+    }
+
+    /// Gives back every lookup reference, for the end of a session. The cwd
+    /// goes back to "/", since its inode is forgotten too.
+    pub fn forget_all(&mut self) -> std::io::Result<()> {
+        let all: Vec<(u64, u64)> = self.lookups.drain().collect();
+        self.recent.clear();
+        self.cwd_inode = 1;
+        self.cwd_path = PathBuf::from("/");
+        self.proto.batch_forget(&all)
+    }
+
     pub fn stat_inode(&mut self, inode: u64) -> std::io::Result<FileStat> {
         let attr_out = self.proto.getattr(inode)?;
-        let a = attr_out.attr;
-
-        Ok(FileStat {
-            inode,
-            size: a.size,
-            mode: a.mode,
-            nlink: a.nlink,
-            uid: a.uid,
-            gid: a.gid,
-
-            blocks: a.blocks,
-            blksize: a.blksize,
-            rdev: a.rdev,
-
-            atime: a.atime,
-            atime_nsec: a.atimensec,
-            mtime: a.mtime,
-            mtime_nsec: a.mtimensec,
-            ctime: a.ctime,
-            ctime_nsec: a.ctimensec,
-        })
+        Ok(FileStat::from_attr(inode, &attr_out.attr))
     }
 
     // This shouldnt be public, right??!
     fn resolve_path(&mut self, path: &str) -> std::io::Result<u64> {
+        Ok(self.resolve(path, true)?.0)
+    }
+
+    /// Walks `path` with one LOOKUP per component and returns the inode plus
+    /// its mode (0 when no lookup was needed, e.g. for "/").
+    ///
+    /// Symlinks in intermediate components are always followed. The final
+    /// component is followed only if `follow_last` is set (stat vs lstat) or
+    /// the path ends in a slash. More than MAX_SYMLINK_HOPS links give ELOOP.
+    fn resolve(&mut self, path: &str, follow_last: bool) -> std::io::Result<(u64, u32)> {
+        let (_, inode, mode) = self.resolve_entry(path, follow_last)?;
+        Ok((inode, mode))
+    }
+
+    /// `resolve`, also returning the directory the final inode was looked up
+    /// in (the start directory if there was no lookup).
+    fn resolve_entry(&mut self, path: &str, follow_last: bool) -> std::io::Result<(u64, u64, u32)> {
         let mut inode = if path.starts_with('/') {
             1
         } else {
             self.cwd_inode
         };
+        let mut parent = inode;
+        let mut mode = 0;
+        let follow_last = follow_last || path.ends_with('/');
 
-        let p: &str = if path.is_empty() { "." } else { path };
+        // Components still to walk, reversed so the next one is at the end.
+        let mut pending: Vec<String> = path
+            .split('/')
+            .filter(|c| !c.is_empty())
+            .rev()
+            .map(String::from)
+            .collect();
+        let mut hops = 0;
 
-        for comp in std::path::Path::new(p).components() {
-            use std::path::Component;
-            match comp {
-                Component::RootDir => {
-                    inode = 1;
-                }
-                Component::CurDir => {}
-                Component::ParentDir => {
+        while let Some(name) = pending.pop() {
+            match name.as_str() {
+                "." => {}
+                ".." => {
                     // for your hello FS, parent of "/" is "/", but use ".." lookup for generality
                     let entry = self.proto.lookup(inode, "..")?;
+                    self.remember(entry.nodeid);
+                    parent = inode;
                     inode = entry.nodeid;
+                    mode = entry.attr.mode;
                 }
-                Component::Normal(name_os) => {
-                    let name = name_os.to_string_lossy().to_string();
+                _ => {
                     let entry = self.proto.lookup(inode, &name)?;
-                    inode = entry.nodeid;
+                    self.remember(entry.nodeid);
+                    let is_link = (entry.attr.mode & libc::S_IFMT) == libc::S_IFLNK;
+
+                    if is_link && (follow_last || !pending.is_empty()) {
+                        hops += 1;
+                        if hops > MAX_SYMLINK_HOPS {
+                            return Err(FuseError::new(libc::ELOOP).into_io());
+                        }
+
+                        // Splice the target in place of the link; `inode` stays
+                        // the directory holding it, which is what relative targets need.
+                        let target = self.proto.readlink(entry.nodeid)?;
+                        if target.starts_with('/') {
+                            inode = 1;
+                            mode = 0;
+                        }
+                        pending.extend(
+                            target
+                                .split('/')
+                                .filter(|c| !c.is_empty())
+                                .rev()
+                                .map(String::from),
+                        );
+                    } else {
+                        parent = inode;
+                        inode = entry.nodeid;
+                        mode = entry.attr.mode;
+                    }
                 }
-                _ => {}
             }
         }
 
-        Ok(inode)
+        Ok((parent, inode, mode))
     }
 
     pub fn chdir(&mut self, path: &str) -> std::io::Result<()> {
@@ -95,10 +310,7 @@ impl<T: FuseTransport> VirtioFsImpl<T> {
         let mode = attr_out.attr.mode;
 
         if (mode & libc::S_IFMT) != libc::S_IFDIR {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "not a directory",
-            ));
+            return Err(std::io::Error::other("not a directory"));
         }
 
         if path.starts_with('/') {
@@ -110,42 +322,706 @@ impl<T: FuseTransport> VirtioFsImpl<T> {
         Ok(())
     }
 
-    pub fn open(&mut self, path: &str, flags: u32) -> std::io::Result<Fd> {
-        let inode = self.resolve_path(path)?;
-        let out = self.proto.open(inode, flags)?;
+    /// Resolves everything but the last component of `path`.
+    /// Returns the parent's inode and the final name.
+    fn resolve_parent(&mut self, path: &str) -> std::io::Result<(u64, String)> {
+        use std::path::Path;
+        let pb = Path::new(path);
+        let parent = pb.parent().unwrap_or(Path::new("."));
+        let name_os = pb
+            .file_name()
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "empty name"))?;
+        let name = name_os.to_string_lossy().to_string();
+
+        let parent_ino = self.resolve_path(parent.to_string_lossy().as_ref())?;
+        Ok((parent_ino, name))
+    }
+
+    /// Opens `path`. With O_CREAT a missing file is created through FUSE_CREATE
+    /// using `mode` filtered by the current umask; O_EXCL makes an existing file an error.
+    pub fn open(&mut self, path: &str, flags: u32, mode: u32) -> std::io::Result<Fd> {
+        // Servers without ATOMIC_O_TRUNC do not expect O_TRUNC in OPEN (the
+        // kernel strips it too); the file is truncated through the new
+        // handle instead.
+        let trunc = flags & libc::O_TRUNC as u32 != 0
+            && !self.proto.session().has(FuseInitFlags::ATOMIC_O_TRUNC);
+        let open_flags = if trunc {
+            flags & !(libc::O_TRUNC as u32)
+        } else {
+            flags
+        };
+
+        let (parent, inode, fh) = if flags & libc::O_CREAT as u32 != 0 {
+            self.open_create(path, open_flags, mode)?
+        } else {
+            let nofollow = flags & libc::O_NOFOLLOW as u32 != 0;
+            let (parent, inode, mode) = self.resolve_entry(path, !nofollow)?;
+
+            if (mode & libc::S_IFMT) == libc::S_IFLNK {
+                return Err(FuseError::new(libc::ELOOP).into_io());
+            }
+            (parent, inode, self.proto.open(inode, open_flags)?.fh)
+        };
 
         let fd = self.next_fd;
         self.next_fd += 1;
 
+        let path = self.cwd_path.join(path).to_string_lossy().into_owned();
         self.open_files.insert(
             fd,
             OpenFile {
+                path,
+                parent,
                 inode,
-                fh: out.fh,
+                fh,
                 offset: 0,
                 flags,
+                lock_owner: Self::lock_owner(fd),
+                flocked: false,
             },
         );
 
+        if trunc && let Err(e) = self.ftruncate(fd, 0) {
+            let _ = self.close(fd);
+            return Err(e);
+        }
+
         Ok(fd)
     }
 
+    /// Returns the parent, inode and file handle.
+    fn open_create(
+        &mut self,
+        path: &str,
+        flags: u32,
+        mode: u32,
+    ) -> std::io::Result<(u64, u64, u64)> {
+        // Without O_EXCL an existing file is simply opened, like open(2)
+        // does, following a symlink in the last component unless
+        // O_NOFOLLOW. Only a name that does not exist yet is created.
+        if flags & libc::O_EXCL as u32 == 0 {
+            let nofollow = flags & libc::O_NOFOLLOW as u32 != 0;
+
+            match self.resolve_entry(path, !nofollow) {
+                Ok((parent, inode, mode)) => {
+                    // mode 0: "/" or ".", which needed no lookup
+                    match mode & libc::S_IFMT {
+                        libc::S_IFLNK => return Err(FuseError::new(libc::ELOOP).into_io()),
+                        0 | libc::S_IFDIR => return Err(FuseError::new(libc::EISDIR).into_io()),
+                        _ => {}
+                    }
+                    let open_flags = flags & !(libc::O_CREAT as u32);
+                    return Ok((parent, inode, self.proto.open(inode, open_flags)?.fh));
+                }
+                Err(e) if errno_of(&e) == Some(libc::ENOENT) => {
+                    // A dangling symlink: open(2) creates its target.
+                    if !nofollow && let Some(target) = self.dangling_target(path)? {
+                        return self.open_create(&target, flags, mode);
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        let (parent_ino, name) = self.resolve_parent(path)?;
+        let mode = libc::S_IFREG | (mode & 0o7777 & !self.umask);
+        let (entry, out) = self
+            .proto
+            .create(parent_ino, &name, flags, mode, self.umask)?;
+        self.remember(entry.nodeid);
+        Ok((parent_ino, entry.nodeid, out.fh))
+    }
+
+    /// If the last component of `path` is a symlink, the path it points
+    /// to, made relative to the link's directory when the target is relative.
+    fn dangling_target(&mut self, path: &str) -> std::io::Result<Option<String>> {
+        // Nothing there at all; CREATE reports whatever is wrong with it.
+        let Ok((inode, mode)) = self.resolve(path, false) else {
+            return Ok(None);
+        };
+        if (mode & libc::S_IFMT) != libc::S_IFLNK {
+            return Ok(None);
+        }
+
+        let target = self.proto.readlink(inode)?;
+        if target.starts_with('/') {
+            return Ok(Some(target));
+        }
+        Ok(Some(match path.trim_end_matches('/').rsplit_once('/') {
+            Some((dir, _)) => format!("{}/{}", dir, target),
+            None => target,
+        }))
+    }
+
     pub fn read(&mut self, fd: Fd, size: u32) -> std::io::Result<Vec<u8>> {
         let of = self
             .open_files
             .get_mut(&fd)
-            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::Other, "bad fd"))?;
+            .ok_or_else(|| std::io::Error::other("bad fd"))?;
 
         let data = self.proto.read(of.inode, of.fh, of.offset, size)?;
         of.offset += data.len() as u64;
         Ok(data)
     }
 
+    pub fn write(&mut self, fd: Fd, data: &[u8]) -> std::io::Result<usize> {
+        let of = self
+            .open_files
+            .get_mut(&fd)
+            .ok_or_else(|| std::io::Error::other("bad fd"))?;
+
+        // O_APPEND: every write lands at the current end of file.
+        if of.flags & libc::O_APPEND as u32 != 0 {
+            of.offset = self.proto.getattr(of.inode)?.attr.size;
+        }
+
+        let written = self.proto.write(of.inode, of.fh, of.offset, data)?;
+        of.offset += written as u64;
+        Ok(written)
+    }
+
+    /// lseek(2). SEEK_SET/CUR/END are resolved locally; SEEK_DATA/SEEK_HOLE
+    /// ask the server. Returns the new file offset.
+    pub fn seek(&mut self, fd: Fd, pos: SeekFrom) -> std::io::Result<u64> {
+        let of = self
+            .open_files
+            .get(&fd)
+            .ok_or_else(|| std::io::Error::other("bad fd"))?;
+        let (inode, fh, cur) = (of.inode, of.fh, of.offset);
+
+        let new = match pos {
+            SeekFrom::Start(off) => Some(off),
+            SeekFrom::Current(delta) => cur.checked_add_signed(delta),
+            SeekFrom::End(delta) => {
+                let size = self.proto.getattr(inode)?.attr.size;
+                size.checked_add_signed(delta)
+            }
+            SeekFrom::Data(off) => Some(self.seek_data_hole(inode, fh, off, libc::SEEK_DATA)?),
+            SeekFrom::Hole(off) => Some(self.seek_data_hole(inode, fh, off, libc::SEEK_HOLE)?),
+        };
+
+        let new = new
+            .filter(|&off| off <= OFFSET_MAX)
+            .ok_or_else(|| FuseError::new(libc::EINVAL).into_io())?;
+
+        // The fd was looked up above and nothing closed it since.
+        self.open_files.get_mut(&fd).unwrap().offset = new;
+        Ok(new)
+    }
+
+    fn seek_data_hole(
+        &mut self,
+        inode: u64,
+        fh: u64,
+        off: u64,
+        whence: i32,
+    ) -> std::io::Result<u64> {
+        if !self.no_lseek {
+            match self.proto.lseek(inode, fh, off, whence as u32) {
+                Err(e) if errno_of(&e) == Some(libc::ENOSYS) => self.no_lseek = true,
+                res => return res,
+            }
+        }
+
+        // Like the kernel's generic_file_llseek: all data, one hole at EOF.
+        let size = self.proto.getattr(inode)?.attr.size;
+        if off >= size {
+            return Err(FuseError::new(libc::ENXIO).into_io());
+        }
+        Ok(if whence == libc::SEEK_DATA { off } else { size })
+    }
+
+    /// Iterates over the data ranges of an open file, skipping holes. The
+    /// file offset is left alone. Servers without LSEEK yield one range
+    /// covering the whole file.
+    pub fn data_segments(&mut self, fd: Fd) -> DataSegments<'_, T> {
+        DataSegments {
+            vfs: self,
+            fd,
+            pos: 0,
+            done: false,
+        }
+    }
+
+    /// Copies the contents of `src` into `dst`, creating or truncating it
+    /// (new files get `src`'s permission bits). The data stays on the server
+    /// through COPY_FILE_RANGE where possible; on ENOSYS or EXDEV it goes
+    /// through a read/write loop instead. Holes in `src` are not copied.
+    /// Returns the number of data bytes copied.
+    pub fn copy(&mut self, src: &str, dst: &str) -> std::io::Result<u64> {
+        let st = self.stat(src)?;
+        if (st.mode & libc::S_IFMT) == libc::S_IFDIR {
+            return Err(FuseError::new(libc::EISDIR).into_io());
+        }
+        // O_TRUNC would destroy the source.
+        if matches!(self.stat(dst), Ok(d) if d.inode == st.inode) {
+            return Err(FuseError::new(libc::EINVAL).into_io());
+        }
+
+        let src_fd = self.open(src, libc::O_RDONLY as u32, 0)?;
+        let flags = libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC;
+        let dst_fd = match self.open(dst, flags as u32, st.mode & 0o7777) {
+            Ok(fd) => fd,
+            Err(e) => {
+                let _ = self.close(src_fd);
+                return Err(e);
+            }
+        };
+
+        let res = self.copy_fds(src_fd, dst_fd, st.size);
+        let dst_closed = self.close(dst_fd);
+        let src_closed = self.close(src_fd);
+
+        let copied = res?;
+        dst_closed.and(src_closed)?;
+        Ok(copied)
+    }
+
+    fn copy_fds(&mut self, src_fd: Fd, dst_fd: Fd, size: u64) -> std::io::Result<u64> {
+        let segments: Vec<_> = self.data_segments(src_fd).collect::<std::io::Result<_>>()?;
+
+        let mut copied = 0;
+        for seg in segments {
+            copied += self.copy_range(src_fd, dst_fd, seg)?;
+        }
+
+        // Skipped holes at the end still count towards the size.
+        self.ftruncate(dst_fd, size)?;
+        Ok(copied)
+    }
+
+    /// Copies `range` to the same offsets in `dst_fd`.
+    fn copy_range(
+        &mut self,
+        src_fd: Fd,
+        dst_fd: Fd,
+        range: std::ops::Range<u64>,
+    ) -> std::io::Result<u64> {
+        let (src_ino, src_fh) = self.handle(src_fd)?;
+        let (dst_ino, dst_fh) = self.handle(dst_fd)?;
+        let mut pos = range.start;
+
+        while pos < range.end && !self.no_copy_range {
+            let input = FuseCopyFileRangeIn {
+                fh_in: src_fh,
+                off_in: pos,
+                nodeid_out: dst_ino,
+                fh_out: dst_fh,
+                off_out: pos,
+                len: (range.end - pos).min(COPY_RANGE_MAX),
+                flags: 0,
+            };
+
+            match self.proto.copy_file_range(src_ino, &input) {
+                Ok(0) => return Ok(pos - range.start), // source shrank
+                Ok(n) => pos += n as u64,
+                Err(e) if errno_of(&e) == Some(libc::ENOSYS) => self.no_copy_range = true,
+                // Only this pair of files can't be copied server-side.
+                Err(e) if errno_of(&e) == Some(libc::EXDEV) => break,
+                Err(e) => return Err(e),
+            }
+        }
+
+        while pos < range.end {
+            let len = (range.end - pos).min(COPY_CHUNK) as u32;
+            let data = self.proto.read(src_ino, src_fh, pos, len)?;
+            if data.is_empty() {
+                break;
+            }
+
+            let written = self.proto.write(dst_ino, dst_fh, pos, &data)?;
+            pos += written as u64;
+            if written < data.len() {
+                return Err(FuseError::new(libc::EIO).into_io());
+            }
+        }
+
+        Ok(pos - range.start)
+    }
+
+    /// (inode, fh) of an open fd.
+    fn handle(&self, fd: Fd) -> std::io::Result<(u64, u64)> {
+        let of = self
+            .open_files
+            .get(&fd)
+            .ok_or_else(|| std::io::Error::other("bad fd"))?;
+        Ok((of.inode, of.fh))
+    }
+
+    /// Lock owner id for `fd`: unique per open file within this process,
+    /// and distinct between processes sharing one server.
+    fn lock_owner(fd: Fd) -> u64 {
+        ((std::process::id() as u64) << 32) | fd as u64
+    }
+
+    /// FLUSH then RELEASE. A FLUSH error (e.g. a deferred write failure) is
+    /// returned, but RELEASE is sent regardless so the handle is not leaked.
     pub fn close(&mut self, fd: Fd) -> std::io::Result<()> {
-        if let Some(of) = self.open_files.remove(&fd) {
-            self.proto.release(of.inode, of.fh)
+        let of = self
+            .open_files
+            .remove(&fd)
+            .ok_or_else(|| std::io::Error::other("bad fd"))?;
+        // The fd doubles as its poll handle.
+        self.proto.release_poll_handle(fd as u64);
+
+        let flushed = match self.proto.flush(of.inode, of.fh, of.lock_owner) {
+            // No FLUSH support means there is nothing to report.
+            Err(e) if errno_of(&e) == Some(libc::ENOSYS) => Ok(()),
+            res => res,
+        };
+        // FLUSH already dropped our POSIX locks; a flock() needs asking for.
+        let (release_flags, lock_owner) = if of.flocked {
+            (FUSE_RELEASE_FLOCK_UNLOCK, of.lock_owner)
+        } else {
+            (0, 0)
+        };
+        let released = self
+            .proto
+            .release(of.inode, of.fh, release_flags, lock_owner);
+
+        flushed.and(released)
+    }
+
+    /// The open fd table, lowest fd first.
+    pub fn open_files(&self) -> Vec<(Fd, &OpenFile)> {
+        let mut files: Vec<(Fd, &OpenFile)> =
+            self.open_files.iter().map(|(&fd, of)| (fd, of)).collect();
+        files.sort_unstable_by_key(|&(fd, _)| fd);
+        files
+    }
+
+    /// Ends the session: closes every open fd (FLUSH + RELEASE), forgets all
+    /// looked-up inodes and sends DESTROY. Runs at most once; Drop calls it
+    /// too. Returns the first error, but always goes through every step.
+    pub fn shutdown(&mut self) -> std::io::Result<()> {
+        if self.shut_down {
+            return Ok(());
+        }
+        self.shut_down = true;
+
+        let mut fds: Vec<Fd> = self.open_files.keys().copied().collect();
+        fds.sort_unstable();
+
+        let mut result = Ok(());
+        for fd in fds {
+            let res = self.close(fd);
+            if result.is_ok() {
+                result = res;
+            }
+        }
+
+        let forgotten = self.forget_all();
+        let destroyed = self.proto.destroy();
+        result.and(forgotten).and(destroyed)
+    }
+
+    /// Places a POSIX record lock on `range` of the file. With `wait` this
+    /// blocks until the lock is granted, otherwise a conflict gives EAGAIN.
+    pub fn lock(
+        &mut self,
+        fd: Fd,
+        range: std::ops::Range<u64>,
+        kind: LockKind,
+        wait: bool,
+    ) -> std::io::Result<()> {
+        let (inode, fh, owner) = self.lock_target(fd)?;
+        let lk = Self::file_lock(&range, Self::lock_type(kind))?;
+
+        if wait {
+            self.proto.setlkw(inode, fh, owner, lk, false)
+        } else {
+            self.proto.setlk(inode, fh, owner, lk, false)
+        }
+    }
+
+    pub fn unlock(&mut self, fd: Fd, range: std::ops::Range<u64>) -> std::io::Result<()> {
+        let (inode, fh, owner) = self.lock_target(fd)?;
+        let lk = Self::file_lock(&range, libc::F_UNLCK as u32)?;
+        self.proto.setlk(inode, fh, owner, lk, false)
+    }
+
+    /// F_GETLK: the first lock that would block `kind` on `range`, if any.
+    pub fn test_lock(
+        &mut self,
+        fd: Fd,
+        range: std::ops::Range<u64>,
+        kind: LockKind,
+    ) -> std::io::Result<Option<LockInfo>> {
+        let (inode, fh, owner) = self.lock_target(fd)?;
+        let lk = Self::file_lock(&range, Self::lock_type(kind))?;
+        let found = self.proto.getlk(inode, fh, owner, lk)?;
+
+        if found.typ == libc::F_UNLCK as u32 {
+            return Ok(None);
+        }
+
+        let end = if found.end >= OFFSET_MAX {
+            u64::MAX
+        } else {
+            found.end + 1
+        };
+        let kind = if found.typ == libc::F_WRLCK as u32 {
+            LockKind::Write
+        } else {
+            LockKind::Read
+        };
+
+        Ok(Some(LockInfo {
+            range: found.start..end,
+            kind,
+            pid: found.pid,
+        }))
+    }
+
+    /// Whole-file flock(2) lock through FUSE_LK_FLOCK. It belongs to this fd
+    /// and is dropped on close.
+    pub fn flock(&mut self, fd: Fd, kind: LockKind, wait: bool) -> std::io::Result<()> {
+        let (inode, fh, owner) = self.lock_target(fd)?;
+        let lk = Self::file_lock(&(0..u64::MAX), Self::lock_type(kind))?;
+
+        if wait {
+            self.proto.setlkw(inode, fh, owner, lk, true)?;
+        } else {
+            self.proto.setlk(inode, fh, owner, lk, true)?;
+        }
+
+        if let Some(of) = self.open_files.get_mut(&fd) {
+            of.flocked = true;
+        }
+        Ok(())
+    }
+
+    pub fn flock_unlock(&mut self, fd: Fd) -> std::io::Result<()> {
+        let (inode, fh, owner) = self.lock_target(fd)?;
+        let lk = Self::file_lock(&(0..u64::MAX), libc::F_UNLCK as u32)?;
+        self.proto.setlk(inode, fh, owner, lk, true)?;
+
+        // Nothing left for close to release.
+        if let Some(of) = self.open_files.get_mut(&fd) {
+            of.flocked = false;
+        }
+        Ok(())
+    }
+
+    fn lock_target(&self, fd: Fd) -> std::io::Result<(u64, u64, u64)> {
+        let of = self
+            .open_files
+            .get(&fd)
+            .ok_or_else(|| std::io::Error::other("bad fd"))?;
+        Ok((of.inode, of.fh, of.lock_owner))
+    }
+
+    fn lock_type(kind: LockKind) -> u32 {
+        match kind {
+            LockKind::Read => libc::F_RDLCK as u32,
+            LockKind::Write => libc::F_WRLCK as u32,
+        }
+    }
+
+    /// Converts a half-open byte range into FUSE's inclusive `start..=end`.
+    /// Ranges reaching past OFFSET_MAX lock through end of file.
+    fn file_lock(range: &std::ops::Range<u64>, typ: u32) -> std::io::Result<FuseFileLock> {
+        if range.start >= range.end || range.start > OFFSET_MAX {
+            return Err(FuseError::new(libc::EINVAL).into_io());
+        }
+
+        Ok(FuseFileLock {
+            start: range.start,
+            end: (range.end - 1).min(OFFSET_MAX),
+            typ,
+            pid: std::process::id(),
+        })
+    }
+
+    /// fsync(2) / fdatasync(2) when `datasync` is set.
+    pub fn fsync(&mut self, fd: Fd, datasync: bool) -> std::io::Result<()> {
+        let of = self
+            .open_files
+            .get(&fd)
+            .ok_or_else(|| std::io::Error::other("bad fd"))?;
+
+        let (inode, fh) = (of.inode, of.fh);
+        Self::ignore_enosys(self.proto.fsync(inode, fh, datasync))
+    }
+
+    /// fallocate(2) on an open file. `mode` is a mix of FALLOC_FL_* bits;
+    /// 0 preallocates and extends the file if the range ends past EOF.
+    pub fn allocate(&mut self, fd: Fd, offset: u64, len: u64, mode: u32) -> std::io::Result<()> {
+        // Same argument checks the kernel does before asking the server.
+        if len == 0 || offset.checked_add(len).is_none_or(|end| end > OFFSET_MAX) {
+            return Err(FuseError::new(libc::EINVAL).into_io());
+        }
+        if mode & FALLOC_FL_PUNCH_HOLE != 0 && mode & FALLOC_FL_KEEP_SIZE == 0 {
+            return Err(FuseError::new(libc::EINVAL).into_io());
+        }
+
+        let of = self
+            .open_files
+            .get(&fd)
+            .ok_or_else(|| std::io::Error::other("bad fd"))?;
+
+        if of.flags & libc::O_ACCMODE as u32 == libc::O_RDONLY as u32 {
+            return Err(FuseError::new(libc::EBADF).into_io());
+        }
+
+        let (inode, fh) = (of.inode, of.fh);
+        self.proto.fallocate(inode, fh, offset, len, mode)
+    }
+
+    /// Deallocates a range; reads from it return zeros afterwards and the
+    /// file size is unchanged.
+    pub fn punch_hole(&mut self, fd: Fd, offset: u64, len: u64) -> std::io::Result<()> {
+        self.allocate(fd, offset, len, FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE)
+    }
+
+    /// ioctl(2) on an open file, encoded the way the kernel does for FUSE:
+    /// the direction and size bits of `cmd` decide how much of `in_buf` is
+    /// sent and how much comes back. Returns the ioctl's return value and
+    /// the first `out_len` bytes of the argument after the call.
+    pub fn ioctl(
+        &mut self,
+        fd: Fd,
+        cmd: u32,
+        in_buf: &[u8],
+        out_len: usize,
+    ) -> std::io::Result<(i32, Vec<u8>)> {
+        self.ioctl_with(fd, 0, cmd, in_buf, out_len)
+    }
+
+    /// Like `ioctl`, but for commands whose argument layout the server
+    /// knows and `cmd` does not describe (e.g. buffers behind pointers).
+    /// The server asks for the parts of the argument it wants; `in_buf` is
+    /// all it can read and `out_len` bounds what it can write.
+    pub fn ioctl_unrestricted(
+        &mut self,
+        fd: Fd,
+        cmd: u32,
+        in_buf: &[u8],
+        out_len: usize,
+    ) -> std::io::Result<(i32, Vec<u8>)> {
+        self.ioctl_with(fd, FUSE_IOCTL_UNRESTRICTED, cmd, in_buf, out_len)
+    }
+
+    fn ioctl_with(
+        &mut self,
+        fd: Fd,
+        flags: u32,
+        cmd: u32,
+        in_buf: &[u8],
+        out_len: usize,
+    ) -> std::io::Result<(i32, Vec<u8>)> {
+        let (inode, fh) = self.handle(fd)?;
+
+        // One buffer stands in for the argument memory; zero-filled past
+        // `in_buf` so the server never sees stale bytes.
+        let mut arg = in_buf.to_vec();
+        let len = out_len.max(in_buf.len()).max(ioc_size(cmd));
+        arg.resize(len, 0);
+
+        let result = self.proto.ioctl(inode, fh, flags, cmd, &mut arg)?;
+        arg.truncate(out_len);
+        Ok((result, arg))
+    }
+
+    /// poll(2) on one open file: waits until any of `events` (POLLIN,
+    /// POLLOUT, ...) is ready or `timeout` passes, and returns the ready
+    /// events, 0 on timeout. `None` waits forever, a zero timeout only
+    /// checks. Between checks we sleep until the server sends a
+    /// FUSE_NOTIFY_POLL wakeup.
+    ///
+    /// Transports that cannot receive notifications (virtio, whose
+    /// notification queue is not wired up) never see that wakeup; there we
+    /// re-poll after 50 ms, backing off to once a second, so readiness can
+    /// be noticed up to that late.
+    pub fn poll(
+        &mut self,
+        fd: Fd,
+        events: u32,
+        timeout: Option<std::time::Duration>,
+    ) -> std::io::Result<u32> {
+        let (inode, fh) = self.handle(fd)?;
+        // fds are never reused, so the fd doubles as the poll handle.
+        let kh = fd as u64;
+        let deadline = timeout.map(|t| std::time::Instant::now() + t);
+
+        loop {
+            if self.no_poll {
+                return Ok(events & DEFAULT_POLLMASK);
+            }
+
+            let now = std::time::Instant::now();
+            let remaining = deadline.map(|d| d.saturating_duration_since(now));
+            let may_wait = remaining.is_none_or(|r| !r.is_zero());
+            let flags = if may_wait {
+                FUSE_POLL_SCHEDULE_NOTIFY
+            } else {
+                0
+            };
+
+            let revents = match self.proto.poll(inode, fh, kh, flags, events) {
+                Err(e) if errno_of(&e) == Some(libc::ENOSYS) => {
+                    self.no_poll = true;
+                    continue;
+                }
+                r => r?,
+            };
+
+            // POLLERR, POLLHUP and POLLNVAL are reported even if not asked for.
+            if revents != 0 || !may_wait {
+                return Ok(revents);
+            }
+
+            self.proto.wait_poll_wakeup(kh, remaining)?;
+        }
+    }
+
+    /// Syncs a directory's entries through a temporary directory handle.
+    pub fn fsync_dir(&mut self, path: &str, datasync: bool) -> std::io::Result<()> {
+        let inode = self.resolve_path(path)?;
+        let fh = self.proto.opendir(inode)?.fh;
+
+        let synced = Self::ignore_enosys(self.proto.fsyncdir(inode, fh, datasync));
+        let released = self.proto.releasedir(inode, fh);
+
+        synced.and(released)
+    }
+
+    /// sync(2): fsyncs every open file, then asks the server to write back
+    /// the whole filesystem with SYNCFS. Servers without SYNCFS get an
+    /// FSYNCDIR of the root instead. Returns the first error but syncs
+    /// everything.
+    pub fn sync_all(&mut self) -> std::io::Result<()> {
+        let mut fds: Vec<Fd> = self.open_files.keys().copied().collect();
+        fds.sort_unstable();
+
+        let mut result = Ok(());
+        for fd in fds {
+            let res = self.fsync(fd, false);
+            if result.is_ok() {
+                result = res;
+            }
+        }
+
+        let res = if self.no_syncfs {
+            self.fsync_dir("/", false)
         } else {
-            Err(std::io::Error::new(std::io::ErrorKind::Other, "bad fd"))
+            match self.proto.syncfs(1) {
+                Err(e) if errno_of(&e) == Some(libc::ENOSYS) => {
+                    self.no_syncfs = true;
+                    self.fsync_dir("/", false)
+                }
+                res => res,
+            }
+        };
+        result.and(res)
+    }
+
+    /// Servers without FSYNC/FSYNCDIR have nothing to write back; like the
+    /// kernel, treat ENOSYS as success.
+    fn ignore_enosys(res: std::io::Result<()>) -> std::io::Result<()> {
+        match res {
+            Err(e) if errno_of(&e) == Some(libc::ENOSYS) => Ok(()),
+            res => res,
         }
     }
 
@@ -167,7 +1043,9 @@ impl<T: FuseTransport> VirtioFsImpl<T> {
                 out.push(DirEntryInfo {
                     name: e.name.clone(),
                     inode: e.ino,
-                    mode: 0, // you can fill this with getattr later
+                    // dirent type is the S_IFMT nibble; permission bits need getattr
+                    mode: e.typ << 12,
+                    stat: None,
                 });
             }
 
@@ -178,45 +1056,1260 @@ impl<T: FuseTransport> VirtioFsImpl<T> {
         Ok(out)
     }
 
-    pub fn mkdir(&mut self, path: &str, mode: u32) -> std::io::Result<()> {
-        use std::path::Path;
-        let pb = Path::new(path);
-        let parent = pb.parent().unwrap_or(Path::new("."));
-        let name_os = pb
-            .file_name()
-            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "empty name"))?;
-        let name = name_os.to_string_lossy().to_string();
+    /// Lists a directory together with each entry's attributes. Uses
+    /// READDIRPLUS when INIT negotiated it, otherwise READDIR plus one
+    /// LOOKUP per entry in the directory itself.
+    pub fn readdir_plus(&mut self, path: &str) -> std::io::Result<Vec<DirEntryInfo>> {
+        let dir_ino = self.resolve_path(path)?;
 
-        let parent_ino = self.resolve_path(parent.to_string_lossy().as_ref())?;
-        let _entry = self.proto.mkdir(parent_ino, &name, mode)?;
+        if self.proto.readdirplus_supported() {
+            match self.readdir_plus_inode(dir_ino) {
+                Err(e) if errno_of(&e) == Some(libc::ENOSYS) => {}
+                res => return res,
+            }
+        }
+
+        let mut out = self.readdir(path)?;
+        for e in out.iter_mut() {
+            if e.name == "." || e.name == ".." {
+                continue;
+            }
+            // An entry that vanished since READDIR is listed without stat.
+            if let Ok(entry) = self.proto.lookup(dir_ino, &e.name) {
+                self.remember(entry.nodeid);
+                let st = FileStat::from_attr(entry.nodeid, &entry.attr);
+                e.mode = st.mode;
+                e.stat = Some(st);
+            }
+        }
+        Ok(out)
+    }
+
+    fn readdir_plus_inode(&mut self, dir_ino: u64) -> std::io::Result<Vec<DirEntryInfo>> {
+        let open = self.proto.opendir(dir_ino)?;
+        let fh = open.fh;
+
+        let mut offset = 0;
+        let mut out = Vec::new();
+
+        let res = loop {
+            let entries = match self.proto.readdirplus(dir_ino, fh, offset, 4096) {
+                Ok(entries) => entries,
+                Err(e) => break Err(e),
+            };
+            if entries.is_empty() {
+                break Ok(());
+            }
+
+            for e in &entries {
+                // Every entry but "." and ".." counts as a LOOKUP.
+                if let Some(ent) = &e.entry
+                    && e.name != "."
+                    && e.name != ".."
+                {
+                    self.remember(ent.nodeid);
+                }
+
+                let stat = e
+                    .entry
+                    .map(|ent| FileStat::from_attr(ent.nodeid, &ent.attr));
+                out.push(DirEntryInfo {
+                    name: e.name.clone(),
+                    inode: e.ino,
+                    mode: stat.as_ref().map_or(e.typ << 12, |st| st.mode),
+                    stat,
+                });
+            }
+
+            offset = entries.last().unwrap().offset;
+        };
+
+        let released = self.proto.releasedir(dir_ino, fh);
+        res.and(released)?;
+        Ok(out)
+    }
+
+    pub fn mkdir(&mut self, path: &str, mode: u32) -> std::io::Result<()> {
+        let (parent_ino, name) = self.resolve_parent(path)?;
+        let entry = self.proto.mkdir(parent_ino, &name, mode)?;
+        self.remember(entry.nodeid);
         Ok(())
     }
 
+    /// mknod(2): `mode` carries the file type (S_IFIFO, S_IFCHR, ...) plus
+    /// permissions, which are filtered by the umask. `rdev` is the device
+    /// number in the kernel's encoding, see `makedev`.
+    pub fn mknod(&mut self, path: &str, mode: u32, rdev: u32) -> std::io::Result<FileStat> {
+        let (parent_ino, name) = self.resolve_parent(path)?;
+        let mode = (mode & libc::S_IFMT) | (mode & 0o7777 & !self.umask);
+
+        let entry = self
+            .proto
+            .mknod(parent_ino, &name, mode, rdev, self.umask)?;
+        self.remember(entry.nodeid);
+        Ok(FileStat::from_attr(entry.nodeid, &entry.attr))
+    }
+
+    /// Packs a device number the way FUSE carries it in a u32 (the kernel's
+    /// new_encode_dev): minor low byte, 12-bit major, then the minor's rest.
+    pub fn makedev(major: u32, minor: u32) -> u32 {
+        (minor & 0xff) | ((major & 0xfff) << 8) | ((minor & !0xff) << 12)
+    }
+
+    pub fn remove_file(&mut self, path: &str) -> std::io::Result<()> {
+        let (parent_ino, name) = self.resolve_parent(path)?;
+        self.proto.unlink(parent_ino, &name)
+    }
+
+    pub fn remove_dir(&mut self, path: &str) -> std::io::Result<()> {
+        let (parent_ino, name) = self.resolve_parent(path)?;
+        self.proto.rmdir(parent_ino, &name)
+    }
+
+    /// Removes `path` and, if it is a directory, everything below it.
+    /// Like `std::fs::remove_dir_all`, a symlink is removed itself rather
+    /// than followed. Keeps going past entries that cannot be removed and
+    /// returns the first error.
+    pub fn remove_dir_all(&mut self, path: &str) -> std::io::Result<()> {
+        self.remove_dir_all_with(path, &mut |_, _| {})
+    }
+
+    /// `remove_dir_all`, passing every failure to `report` along with the
+    /// path it happened on, like `rm -r` reports each entry it cannot remove.
+    pub fn remove_dir_all_with(
+        &mut self,
+        path: &str,
+        report: &mut dyn FnMut(&str, &std::io::Error),
+    ) -> std::io::Result<()> {
+        let mode = self.lstat(path).inspect_err(|e| report(path, e))?.mode;
+        self.remove_entry(path, mode, report)
+    }
+
+    /// Empties the directory `path` and then removes it.
+    fn remove_tree(
+        &mut self,
+        path: &str,
+        report: &mut dyn FnMut(&str, &std::io::Error),
+    ) -> std::io::Result<()> {
+        let entries = self.readdir(path).inspect_err(|e| report(path, e))?;
+        let mut first_err = None;
+
+        for e in entries {
+            if e.name == "." || e.name == ".." {
+                continue;
+            }
+
+            let child = format!("{}/{}", path.trim_end_matches('/'), e.name);
+            if let Err(err) = self.remove_entry(&child, e.mode, report) {
+                first_err.get_or_insert(err);
+            }
+        }
+
+        // Still try, so an error that left the directory non-empty is
+        // followed by the ENOTEMPTY it causes, as rm does.
+        if let Err(err) = self.remove_dir(path) {
+            report(path, &err);
+            first_err.get_or_insert(err);
+        }
+
+        first_err.map_or(Ok(()), Err)
+    }
+
+    /// One entry of `remove_dir_all_with`; `mode` is its lstat or readdir type.
+    fn remove_entry(
+        &mut self,
+        path: &str,
+        mode: u32,
+        report: &mut dyn FnMut(&str, &std::io::Error),
+    ) -> std::io::Result<()> {
+        // Servers may report DT_UNKNOWN, in which case ask for the real type.
+        let mode = if mode != 0 {
+            mode
+        } else {
+            self.lstat(path).inspect_err(|e| report(path, e))?.mode
+        };
+
+        if (mode & libc::S_IFMT) == libc::S_IFDIR {
+            // Reports its own failures.
+            self.remove_tree(path, report)
+        } else {
+            self.remove_file(path).inspect_err(|e| report(path, e))
+        }
+    }
+
+    /// Renames `from` to `to`. `flags` takes the RENAME_* constants from
+    /// `crate::protocol`; servers without FUSE_RENAME2 still handle `flags == 0`.
+    pub fn rename(&mut self, from: &str, to: &str, flags: u32) -> std::io::Result<()> {
+        let (olddir, oldname) = self.resolve_parent(from)?;
+        let (newdir, newname) = self.resolve_parent(to)?;
+
+        match self
+            .proto
+            .rename2(olddir, &oldname, newdir, &newname, flags)
+        {
+            Err(e) if flags == 0 && errno_of(&e) == Some(libc::ENOSYS) => {
+                self.proto.rename(olddir, &oldname, newdir, &newname)
+            }
+            res => res,
+        }
+    }
+
+    /// Like `stat`, but a symlink in the final component is reported itself.
+    pub fn lstat(&mut self, path: &str) -> std::io::Result<FileStat> {
+        let (inode, _) = self.resolve(path, false)?;
+        self.stat_inode(inode)
+    }
+
     pub fn stat(&mut self, path: &str) -> std::io::Result<FileStat> {
         let inode = self.resolve_path(path)?;
-        let attr_out = self.proto.getattr(inode)?;
-        let a = attr_out.attr;
+        self.stat_inode(inode)
+    }
 
-        Ok(FileStat {
-            inode,
-            size: a.size,
-            mode: a.mode,
-            nlink: a.nlink,
-            uid: a.uid,
-            gid: a.gid,
+    /// Creates `link` as a symlink to `target`. The target is stored as-is.
+    pub fn symlink(&mut self, target: &str, link: &str) -> std::io::Result<()> {
+        let (parent_ino, name) = self.resolve_parent(link)?;
+        let entry = self.proto.symlink(parent_ino, &name, target)?;
+        self.remember(entry.nodeid);
+        Ok(())
+    }
 
-            blocks: a.blocks,
-            blksize: a.blksize,
-            rdev: a.rdev,
+    /// Creates `dst` as a hard link to `src` and returns the linked file's
+    /// attributes from the LINK reply, so the bumped nlink is already there.
+    /// Like link(2), a symlink in `src` is linked itself, not its target.
+    pub fn hard_link(&mut self, src: &str, dst: &str) -> std::io::Result<FileStat> {
+        let (src_ino, src_mode) = self.resolve(src, false)?;
+        let (parent_ino, name) = self.resolve_parent(dst)?;
 
-            atime: a.atime,
-            atime_nsec: a.atimensec,
+        let entry = self.proto.link(src_ino, parent_ino, &name)?;
+        self.remember(entry.nodeid);
 
-            mtime: a.mtime,
-            mtime_nsec: a.mtimensec,
+        // Same sanity check the kernel does: the new entry must be the same
+        // kind of file we linked.
+        if entry.nodeid == 0 || (src_mode != 0 && (entry.attr.mode ^ src_mode) & libc::S_IFMT != 0)
+        {
+            return Err(FuseError::new(libc::EIO).into_io());
+        }
+
+        Ok(FileStat::from_attr(entry.nodeid, &entry.attr))
+    }
 
-            ctime: a.ctime,
-            ctime_nsec: a.ctimensec,
+    pub fn readlink(&mut self, path: &str) -> std::io::Result<String> {
+        let (inode, _) = self.resolve(path, false)?;
+        self.proto.readlink(inode)
+    }
+
+    /// Statistics for the filesystem containing `path`.
+    pub fn statfs(&mut self, path: &str) -> std::io::Result<FsStat> {
+        let inode = self.resolve_path(path)?;
+        let st = self.proto.statfs(inode)?.st;
+
+        Ok(FsStat {
+            blocks: st.blocks,
+            bfree: st.bfree,
+            bavail: st.bavail,
+            files: st.files,
+            ffree: st.ffree,
+            bsize: st.bsize,
+            namelen: st.namelen,
+            frsize: st.frsize,
         })
     }
+
+    /// access(2): `mask` is F_OK or any of R_OK | W_OK | X_OK.
+    /// Asks the server through FUSE_ACCESS; if it does not implement that,
+    /// checks the mode bits against our uid/gid instead.
+    pub fn access(&mut self, path: &str, mask: u32) -> std::io::Result<()> {
+        let inode = self.resolve_path(path)?;
+
+        // Resolving the path already proved existence.
+        if mask == libc::F_OK as u32 {
+            return Ok(());
+        }
+
+        if !self.no_access {
+            match self.proto.access(inode, mask) {
+                Err(e) if errno_of(&e) == Some(libc::ENOSYS) => self.no_access = true,
+                res => return res,
+            }
+        }
+
+        let attr = self.proto.getattr(inode)?.attr;
+        Self::check_access(&attr, mask)
+    }
+
+    pub fn can_read(&mut self, path: &str) -> bool {
+        self.access(path, libc::R_OK as u32).is_ok()
+    }
+
+    /// Local fallback for `access`, using the same credentials we put in every
+    /// fuse_in_header (real uid/gid plus supplementary groups).
+    fn check_access(attr: &FuseAttr, mask: u32) -> std::io::Result<()> {
+        let uid = unsafe { libc::getuid() } as u32;
+        let gid = unsafe { libc::getgid() } as u32;
+        let mask = mask & 0o7;
+
+        let granted = if uid == 0 {
+            // root may read and write anything, but only execute files that
+            // have at least one x bit (directories are always searchable).
+            let is_dir = (attr.mode & libc::S_IFMT) == libc::S_IFDIR;
+            if is_dir || attr.mode & 0o111 != 0 {
+                0o7
+            } else {
+                0o6
+            }
+        } else if attr.uid == uid {
+            (attr.mode >> 6) & 0o7
+        } else if attr.gid == gid || Self::in_group(attr.gid) {
+            (attr.mode >> 3) & 0o7
+        } else {
+            attr.mode & 0o7
+        };
+
+        if granted & mask == mask {
+            Ok(())
+        } else {
+            Err(FuseError::new(libc::EACCES).into_io())
+        }
+    }
+
+    fn in_group(gid: u32) -> bool {
+        let n = unsafe { libc::getgroups(0, std::ptr::null_mut()) };
+        if n <= 0 {
+            return false;
+        }
+
+        let mut groups = vec![0 as libc::gid_t; n as usize];
+        let n = unsafe { libc::getgroups(n, groups.as_mut_ptr()) };
+        groups.truncate(n.max(0) as usize);
+
+        groups.contains(&gid)
+    }
+
+    /// Sets extended attribute `name`; `flags` takes XATTR_CREATE / XATTR_REPLACE.
+    pub fn set_xattr(
+        &mut self,
+        path: &str,
+        name: &str,
+        value: &[u8],
+        flags: u32,
+    ) -> std::io::Result<()> {
+        let inode = self.resolve_path(path)?;
+        self.proto.setxattr(inode, name, value, flags)
+    }
+
+    pub fn get_xattr(&mut self, path: &str, name: &str) -> std::io::Result<Vec<u8>> {
+        let inode = self.resolve_path(path)?;
+        self.proto.getxattr(inode, name)
+    }
+
+    pub fn list_xattr(&mut self, path: &str) -> std::io::Result<Vec<String>> {
+        let inode = self.resolve_path(path)?;
+        self.proto.listxattr(inode)
+    }
+
+    pub fn remove_xattr(&mut self, path: &str, name: &str) -> std::io::Result<()> {
+        let inode = self.resolve_path(path)?;
+        self.proto.removexattr(inode, name)
+    }
+
+    fn setattr_path(&mut self, path: &str, attr: &FuseSetattrIn) -> std::io::Result<FileStat> {
+        let inode = self.resolve_path(path)?;
+        let out = self.proto.setattr(inode, attr)?;
+        Ok(FileStat::from_attr(inode, &out.attr))
+    }
+
+    /// chmod: only the permission bits (0o7777) of `mode` are used.
+    pub fn set_permissions(&mut self, path: &str, mode: u32) -> std::io::Result<FileStat> {
+        let mut attr = FuseSetattrIn::new();
+        attr.valid = FATTR_MODE;
+        attr.mode = mode & 0o7777;
+        self.setattr_path(path, &attr)
+    }
+
+    /// chown: `None` leaves the uid or gid unchanged.
+    pub fn chown(
+        &mut self,
+        path: &str,
+        uid: Option<u32>,
+        gid: Option<u32>,
+    ) -> std::io::Result<FileStat> {
+        let mut attr = FuseSetattrIn::new();
+        if let Some(uid) = uid {
+            attr.valid |= FATTR_UID;
+            attr.uid = uid;
+        }
+        if let Some(gid) = gid {
+            attr.valid |= FATTR_GID;
+            attr.gid = gid;
+        }
+        self.setattr_path(path, &attr)
+    }
+
+    pub fn truncate(&mut self, path: &str, size: u64) -> std::io::Result<FileStat> {
+        let mut attr = FuseSetattrIn::new();
+        attr.valid = FATTR_SIZE;
+        attr.size = size;
+        self.setattr_path(path, &attr)
+    }
+
+    /// Truncates an open file. The fh is sent along so the server can use
+    /// the open handle rather than reopening by inode.
+    pub fn ftruncate(&mut self, fd: Fd, size: u64) -> std::io::Result<FileStat> {
+        let of = self
+            .open_files
+            .get(&fd)
+            .ok_or_else(|| std::io::Error::other("bad fd"))?;
+
+        let mut attr = FuseSetattrIn::new();
+        attr.valid = FATTR_SIZE | FATTR_FH;
+        attr.size = size;
+        attr.fh = of.fh;
+
+        let inode = of.inode;
+        let out = self.proto.setattr(inode, &attr)?;
+        Ok(FileStat::from_attr(inode, &out.attr))
+    }
+
+    /// utimens: `None` leaves that timestamp unchanged.
+    pub fn set_times(
+        &mut self,
+        path: &str,
+        atime: Option<SetTime>,
+        mtime: Option<SetTime>,
+    ) -> std::io::Result<FileStat> {
+        let mut attr = FuseSetattrIn::new();
+
+        match atime {
+            Some(SetTime::Now) => attr.valid |= FATTR_ATIME | FATTR_ATIME_NOW,
+            Some(SetTime::At { sec, nsec }) => {
+                attr.valid |= FATTR_ATIME;
+                attr.atime = sec;
+                attr.atimensec = nsec;
+            }
+            None => {}
+        }
+
+        match mtime {
+            Some(SetTime::Now) => attr.valid |= FATTR_MTIME | FATTR_MTIME_NOW,
+            Some(SetTime::At { sec, nsec }) => {
+                attr.valid |= FATTR_MTIME;
+                attr.mtime = sec;
+                attr.mtimensec = nsec;
+            }
+            None => {}
+        }
+
+        self.setattr_path(path, &attr)
+    }
+}
+
+impl<T: FuseTransport> Drop for VirtioFsImpl<T> {
+    fn drop(&mut self) {
+        // Nobody is left to report errors to.
+        let _ = self.shutdown();
+    }
+}
+
+/// Iterator returned by `VirtioFsImpl::data_segments`.
+pub struct DataSegments<'a, T: FuseTransport> {
+    vfs: &'a mut VirtioFsImpl<T>,
+    fd: Fd,
+    pos: u64,
+    done: bool,
+}
+
+impl<T: FuseTransport> DataSegments<'_, T> {
+    fn next_segment(&mut self) -> std::io::Result<Option<std::ops::Range<u64>>> {
+        let of = self
+            .vfs
+            .open_files
+            .get(&self.fd)
+            .ok_or_else(|| std::io::Error::other("bad fd"))?;
+        let (inode, fh) = (of.inode, of.fh);
+
+        // ENXIO: no data left before EOF.
+        let start = match self
+            .vfs
+            .seek_data_hole(inode, fh, self.pos, libc::SEEK_DATA)
+        {
+            Err(e) if errno_of(&e) == Some(libc::ENXIO) => return Ok(None),
+            res => res?,
+        };
+        let end = self.vfs.seek_data_hole(inode, fh, start, libc::SEEK_HOLE)?;
+
+        // A server that never moves forward would have us loop forever.
+        if end <= start {
+            return Err(FuseError::new(libc::EIO).into_io());
+        }
+
+        self.pos = end;
+        Ok(Some(start..end))
+    }
+}
+
+impl<T: FuseTransport> Iterator for DataSegments<'_, T> {
+    type Item = std::io::Result<std::ops::Range<u64>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let res = self.next_segment().transpose();
+        if !matches!(res, Some(Ok(_))) {
+            self.done = true;
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::opcodes::FuseOpcode;
+    use crate::transport::mock::{MockTransport, Request};
+    use crate::transport::unix_socket::FuseStream;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    type Vfs = VirtioFsImpl<FuseStream>;
+
+    /// fuse_entry_out for `nodeid` with `mode`.
+    fn entry_out(nodeid: u64, mode: u32) -> Vec<u8> {
+        let mut out = vec![0u8; 128];
+        out[..8].copy_from_slice(&nodeid.to_le_bytes());
+        out[40..48].copy_from_slice(&nodeid.to_le_bytes());
+        out[100..104].copy_from_slice(&mode.to_le_bytes());
+        out
+    }
+
+    /// Directory contents by nodeid: `(name, nodeid, mode)`.
+    type Tree = Rc<std::cell::RefCell<HashMap<u64, Vec<(String, u64, u32)>>>>;
+
+    fn name_of(req: &Request) -> String {
+        let end = req.body.iter().position(|&b| b == 0).unwrap();
+        String::from_utf8(req.body[..end].to_vec()).unwrap()
+    }
+
+    /// A server holding `tree`, where UNLINK of "locked" fails with EACCES.
+    /// READDIR returns everything in one go.
+    fn tree_server(tree: Tree) -> MockTransport {
+        MockTransport::new(move |req: &Request| {
+            let mut tree = tree.borrow_mut();
+            let dir = tree.entry(req.nodeid).or_default();
+            let find = |name: &str| dir.iter().position(|(n, _, _)| n == name);
+
+            match req.opcode {
+                op if op == FuseOpcode::Lookup as u32 => {
+                    let (_, nodeid, mode) = dir[find(&name_of(req)).ok_or(libc::ENOENT)?];
+                    Ok(entry_out(nodeid, mode))
+                }
+                op if op == FuseOpcode::Readdir as u32 => {
+                    let offset = u64::from_le_bytes(req.body[8..16].try_into().unwrap());
+                    let mut buf = Vec::new();
+                    for (i, (name, nodeid, mode)) in dir.iter().enumerate().skip(offset as usize) {
+                        buf.extend_from_slice(&nodeid.to_le_bytes());
+                        buf.extend_from_slice(&(i as u64 + 1).to_le_bytes());
+                        buf.extend_from_slice(&(name.len() as u32).to_le_bytes());
+                        buf.extend_from_slice(&(mode >> 12).to_le_bytes());
+                        buf.extend_from_slice(name.as_bytes());
+                        buf.resize(buf.len().next_multiple_of(8), 0);
+                    }
+                    Ok(buf)
+                }
+                op if op == FuseOpcode::Unlink as u32 => {
+                    let name = name_of(req);
+                    if name == "locked" {
+                        return Err(libc::EACCES);
+                    }
+                    dir.remove(find(&name).ok_or(libc::ENOENT)?);
+                    Ok(Vec::new())
+                }
+                op if op == FuseOpcode::Rmdir as u32 => {
+                    let i = find(&name_of(req)).ok_or(libc::ENOENT)?;
+                    let child = dir[i].1;
+                    if tree.get(&child).is_some_and(|c| !c.is_empty()) {
+                        return Err(libc::ENOTEMPTY);
+                    }
+                    tree.get_mut(&req.nodeid).unwrap().remove(i);
+                    Ok(Vec::new())
+                }
+                op if op == FuseOpcode::Getattr as u32 => {
+                    let mode = tree
+                        .values()
+                        .flatten()
+                        .find(|(_, nodeid, _)| *nodeid == req.nodeid)
+                        .map_or(libc::S_IFDIR | 0o755, |(_, _, mode)| *mode);
+                    let mut out = vec![0u8; 104];
+                    out[16..24].copy_from_slice(&req.nodeid.to_le_bytes());
+                    out[76..80].copy_from_slice(&mode.to_le_bytes());
+                    Ok(out)
+                }
+                op if op == FuseOpcode::Opendir as u32 => Ok(vec![0u8; 16]),
+                _ => Ok(Vec::new()),
+            }
+        })
+    }
+
+    /// A server with one file, "/f", that turns readable on the
+    /// `ready_after`th POLL. Its transport cannot receive notifications.
+    fn poll_server(ready_after: u32) -> (MockTransport, Rc<Cell<u32>>) {
+        let polls = Rc::new(Cell::new(0));
+        let counter = polls.clone();
+        let t = MockTransport::new(move |req: &Request| match req.opcode {
+            op if op == FuseOpcode::Lookup as u32 => Ok(entry_out(2, libc::S_IFREG | 0o644)),
+            op if op == FuseOpcode::Open as u32 => Ok(vec![0u8; 16]),
+            op if op == FuseOpcode::Poll as u32 => {
+                counter.set(counter.get() + 1);
+                let revents = if counter.get() >= ready_after {
+                    libc::POLLIN as u32
+                } else {
+                    0
+                };
+                Ok([revents.to_le_bytes(), [0; 4]].concat())
+            }
+            _ => Ok(Vec::new()),
+        })
+        .roundtrip_only();
+        (t, polls)
+    }
+
+    fn opcodes(sent: &std::cell::RefCell<Vec<Request>>) -> Vec<u32> {
+        sent.borrow().iter().map(|r| r.opcode).collect()
+    }
+
+    /// A server where only "/f" (nodeid 2) exists. CREATE makes nodeid 3
+    /// unless the name is taken.
+    fn create_server() -> MockTransport {
+        MockTransport::new(|req: &Request| match req.opcode {
+            op if op == FuseOpcode::Lookup as u32 => match name_of(req).as_str() {
+                "f" => Ok(entry_out(2, libc::S_IFREG | 0o644)),
+                _ => Err(libc::ENOENT),
+            },
+            op if op == FuseOpcode::Create as u32 => {
+                let end = req.body[16..].iter().position(|&b| b == 0).unwrap();
+                if &req.body[16..16 + end] == b"f" {
+                    return Err(libc::EEXIST);
+                }
+                Ok([entry_out(3, libc::S_IFREG | 0o644), vec![0u8; 16]].concat())
+            }
+            op if op == FuseOpcode::Open as u32 => Ok(vec![0u8; 16]),
+            _ => Ok(Vec::new()),
+        })
+    }
+
+    #[test]
+    fn o_creat_sends_create_with_the_umask_applied() {
+        let t = create_server();
+        let sent = t.sent.clone();
+        let mut vfs = VirtioFsImpl::new(FuseProtocol::new(t));
+        vfs.umask(0o027);
+
+        let flags = (libc::O_WRONLY | libc::O_CREAT) as u32;
+        vfs.open("/new", flags, 0o666).unwrap();
+
+        let sent = sent.borrow();
+        let create = sent.last().unwrap();
+        assert_eq!(create.opcode, FuseOpcode::Create as u32);
+        let word = |i: usize| u32::from_le_bytes(create.body[i * 4..i * 4 + 4].try_into().unwrap());
+        assert_eq!(word(1), libc::S_IFREG | 0o640);
+        assert_eq!(word(2), 0o027);
+    }
+
+    #[test]
+    fn o_creat_opens_an_existing_file() {
+        let t = create_server();
+        let sent = t.sent.clone();
+        let mut vfs = VirtioFsImpl::new(FuseProtocol::new(t));
+
+        let flags = (libc::O_WRONLY | libc::O_CREAT) as u32;
+        vfs.open("/f", flags, 0o644).unwrap();
+
+        let ops = opcodes(&sent);
+        assert_eq!(ops, [FuseOpcode::Lookup as u32, FuseOpcode::Open as u32]);
+    }
+
+    #[test]
+    fn o_excl_fails_on_an_existing_file() {
+        let t = create_server();
+        let sent = t.sent.clone();
+        let mut vfs = VirtioFsImpl::new(FuseProtocol::new(t));
+
+        let flags = (libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL) as u32;
+        let err = vfs.open("/f", flags, 0o644).unwrap_err();
+        assert_eq!(errno_of(&err), Some(libc::EEXIST));
+
+        // Straight to CREATE, so the server decides atomically.
+        assert_eq!(opcodes(&sent), [FuseOpcode::Create as u32]);
+        assert!(vfs.open_files().is_empty());
+    }
+
+    #[test]
+    fn rename_falls_back_to_rename_without_rename2() {
+        let t = MockTransport::new(|req: &Request| match req.opcode {
+            op if op == FuseOpcode::Rename2 as u32 => Err(libc::ENOSYS),
+            _ => Ok(Vec::new()),
+        });
+        let sent = t.sent.clone();
+        let mut vfs = VirtioFsImpl::new(FuseProtocol::new(t));
+
+        vfs.rename("/a", "/b", 0).unwrap();
+        let (rename2, rename) = (FuseOpcode::Rename2 as u32, FuseOpcode::Rename as u32);
+        assert_eq!(opcodes(&sent), [rename2, rename]);
+
+        // RENAME cannot honour flags, so those fail instead.
+        let err = vfs
+            .rename("/a", "/b", crate::protocol::RENAME_NOREPLACE)
+            .unwrap_err();
+        assert_eq!(errno_of(&err), Some(libc::ENOSYS));
+        assert_eq!(opcodes(&sent), [rename2, rename, rename2]);
+    }
+
+    /// "/f" (nodeid 2) at the end of a chain of symlinks: "x" -> "l0",
+    /// "l0" -> "l1", ..., "l39" -> "f".
+    fn symlink_chain_server() -> MockTransport {
+        MockTransport::new(|req: &Request| match req.opcode {
+            op if op == FuseOpcode::Lookup as u32 => match name_of(req).as_str() {
+                "f" => Ok(entry_out(2, libc::S_IFREG | 0o644)),
+                "x" => Ok(entry_out(99, libc::S_IFLNK | 0o777)),
+                name => {
+                    let n: u64 = name[1..].parse().map_err(|_| libc::ENOENT)?;
+                    Ok(entry_out(100 + n, libc::S_IFLNK | 0o777))
+                }
+            },
+            op if op == FuseOpcode::Readlink as u32 => Ok(match req.nodeid {
+                99 => b"l0".to_vec(),
+                139 => b"f".to_vec(),
+                n => format!("l{}", n - 99).into_bytes(),
+            }),
+            _ => Ok(Vec::new()),
+        })
+    }
+
+    #[test]
+    fn forty_symlinks_resolve() {
+        let mut vfs = VirtioFsImpl::new(FuseProtocol::new(symlink_chain_server()));
+        assert_eq!(vfs.resolve_path("/l0").unwrap(), 2);
+    }
+
+    #[test]
+    fn forty_one_symlinks_are_eloop() {
+        let t = symlink_chain_server();
+        let sent = t.sent.clone();
+        let mut vfs = VirtioFsImpl::new(FuseProtocol::new(t));
+
+        let err = vfs.resolve_path("/x").unwrap_err();
+        assert_eq!(errno_of(&err), Some(libc::ELOOP));
+        // The 41st link is not even read.
+        let readlink = FuseOpcode::Readlink as u32;
+        assert_eq!(
+            opcodes(&sent).iter().filter(|&&op| op == readlink).count(),
+            40
+        );
+
+        // Without following the last one, "x" is just a symlink.
+        let (_, mode) = vfs.resolve("/x", false).unwrap();
+        assert_eq!(mode & libc::S_IFMT, libc::S_IFLNK);
+    }
+
+    #[test]
+    fn hard_link_checks_the_linked_type() {
+        let t = MockTransport::new(|req: &Request| match req.opcode {
+            op if op == FuseOpcode::Lookup as u32 => Ok(entry_out(2, libc::S_IFREG | 0o644)),
+            op if op == FuseOpcode::Link as u32 => match &req.body[8..] {
+                b"g\0" => Ok(entry_out(2, libc::S_IFREG | 0o644)),
+                _ => Ok(entry_out(2, libc::S_IFDIR | 0o755)),
+            },
+            _ => Ok(Vec::new()),
+        });
+        let sent = t.sent.clone();
+        let mut vfs = VirtioFsImpl::new(FuseProtocol::new(t));
+
+        let st = vfs.hard_link("/f", "/g").unwrap();
+        assert_eq!(st.inode, 2);
+        let link = sent.borrow().last().unwrap().clone();
+        assert_eq!((link.opcode, link.nodeid), (FuseOpcode::Link as u32, 1));
+        assert_eq!(u64::from_le_bytes(link.body[..8].try_into().unwrap()), 2);
+
+        // A server answering with a directory is broken.
+        let err = vfs.hard_link("/f", "/h").err().unwrap();
+        assert_eq!(errno_of(&err), Some(libc::EIO));
+    }
+
+    /// The fuse_setattr_in of the last request sent.
+    fn last_setattr(sent: &std::cell::RefCell<Vec<Request>>) -> FuseSetattrIn {
+        let sent = sent.borrow();
+        let req = sent.last().unwrap();
+        assert_eq!(req.opcode, FuseOpcode::Setattr as u32);
+        bytemuck::pod_read_unaligned(&req.body)
+    }
+
+    #[test]
+    fn setattr_sets_only_the_fields_it_changes() {
+        let t = MockTransport::new(|req: &Request| match req.opcode {
+            op if op == FuseOpcode::Lookup as u32 => Ok(entry_out(2, libc::S_IFREG | 0o644)),
+            op if op == FuseOpcode::Setattr as u32 => Ok(vec![0u8; 104]),
+            _ => Ok(Vec::new()),
+        });
+        let sent = t.sent.clone();
+        let mut vfs = VirtioFsImpl::new(FuseProtocol::new(t));
+
+        vfs.set_permissions("/f", libc::S_IFREG | 0o4755).unwrap();
+        let attr = last_setattr(&sent);
+        assert_eq!((attr.valid, attr.mode), (FATTR_MODE, 0o4755));
+
+        vfs.chown("/f", None, Some(100)).unwrap();
+        let attr = last_setattr(&sent);
+        assert_eq!((attr.valid, attr.gid), (FATTR_GID, 100));
+
+        vfs.truncate("/f", 10).unwrap();
+        let attr = last_setattr(&sent);
+        assert_eq!((attr.valid, attr.size), (FATTR_SIZE, 10));
+
+        let mtime = SetTime::At { sec: 7, nsec: 8 };
+        vfs.set_times("/f", Some(SetTime::Now), Some(mtime))
+            .unwrap();
+        let attr = last_setattr(&sent);
+        assert_eq!(attr.valid, FATTR_ATIME | FATTR_ATIME_NOW | FATTR_MTIME);
+        assert_eq!((attr.mtime, attr.mtimensec), (7, 8));
+    }
+
+    #[test]
+    fn statfs_asks_about_the_inode_of_the_path() {
+        let t = MockTransport::new(|req: &Request| match req.opcode {
+            op if op == FuseOpcode::Lookup as u32 => Ok(entry_out(5, libc::S_IFDIR | 0o755)),
+            op if op == FuseOpcode::Statfs as u32 => {
+                // fuse_kstatfs: five u64 counts, then bsize, namelen, frsize.
+                let mut out = vec![0u8; 80];
+                for (i, n) in [100u64, 40, 30, 1000, 900].iter().enumerate() {
+                    out[i * 8..i * 8 + 8].copy_from_slice(&n.to_le_bytes());
+                }
+                for (i, n) in [4096u32, 255, 512].iter().enumerate() {
+                    out[40 + i * 4..44 + i * 4].copy_from_slice(&n.to_le_bytes());
+                }
+                Ok(out)
+            }
+            _ => Ok(Vec::new()),
+        });
+        let sent = t.sent.clone();
+        let mut vfs = VirtioFsImpl::new(FuseProtocol::new(t));
+
+        let st = vfs.statfs("/d").unwrap();
+        assert_eq!(sent.borrow().last().unwrap().nodeid, 5);
+        assert_eq!((st.blocks, st.bfree, st.bavail), (100, 40, 30));
+        assert_eq!((st.files, st.ffree), (1000, 900));
+        assert_eq!((st.bsize, st.namelen, st.frsize), (4096, 255, 512));
+    }
+
+    /// "/f" opens fine; FLUSH fails with `flush_errno`.
+    fn flush_server(flush_errno: i32) -> MockTransport {
+        MockTransport::new(move |req: &Request| match req.opcode {
+            op if op == FuseOpcode::Lookup as u32 => Ok(entry_out(2, libc::S_IFREG | 0o644)),
+            op if op == FuseOpcode::Open as u32 => Ok(vec![0u8; 16]),
+            op if op == FuseOpcode::Flush as u32 => Err(flush_errno),
+            _ => Ok(Vec::new()),
+        })
+    }
+
+    #[test]
+    fn close_flushes_then_releases_and_reports_the_flush_error() {
+        let t = flush_server(libc::EIO);
+        let sent = t.sent.clone();
+        let mut vfs = VirtioFsImpl::new(FuseProtocol::new(t));
+
+        let fd = vfs.open("/f", libc::O_WRONLY as u32, 0).unwrap();
+        let err = vfs.close(fd).unwrap_err();
+        assert_eq!(errno_of(&err), Some(libc::EIO));
+        assert_eq!(
+            opcodes(&sent)[2..],
+            [FuseOpcode::Flush as u32, FuseOpcode::Release as u32]
+        );
+
+        // The fd is gone all the same.
+        assert!(vfs.close(fd).is_err());
+    }
+
+    #[test]
+    fn close_ignores_a_server_without_flush() {
+        let mut vfs = VirtioFsImpl::new(FuseProtocol::new(flush_server(libc::ENOSYS)));
+        let fd = vfs.open("/f", libc::O_WRONLY as u32, 0).unwrap();
+        vfs.close(fd).unwrap();
+    }
+
+    #[test]
+    fn access_checks_locally_without_server_support() {
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        let t = MockTransport::new(move |req: &Request| match req.opcode {
+            op if op == FuseOpcode::Lookup as u32 => Ok(entry_out(2, libc::S_IFREG | 0o644)),
+            op if op == FuseOpcode::Access as u32 => Err(libc::ENOSYS),
+            op if op == FuseOpcode::Getattr as u32 => {
+                // Our own rw-r--r-- file.
+                let mut out = vec![0u8; 104];
+                out[76..80].copy_from_slice(&(libc::S_IFREG | 0o644).to_le_bytes());
+                out[84..88].copy_from_slice(&uid.to_le_bytes());
+                out[88..92].copy_from_slice(&gid.to_le_bytes());
+                Ok(out)
+            }
+            _ => Ok(Vec::new()),
+        });
+        let sent = t.sent.clone();
+        let mut vfs = VirtioFsImpl::new(FuseProtocol::new(t));
+
+        vfs.access("/f", (libc::R_OK | libc::W_OK) as u32).unwrap();
+        // Nobody may execute a file without x bits, not even root.
+        let err = vfs.access("/f", libc::X_OK as u32).unwrap_err();
+        assert_eq!(errno_of(&err), Some(libc::EACCES));
+
+        // ACCESS is only tried once.
+        let access = FuseOpcode::Access as u32;
+        assert_eq!(opcodes(&sent).iter().filter(|&&op| op == access).count(), 1);
+    }
+
+    #[test]
+    fn mknod_keeps_the_file_type_and_applies_the_umask() {
+        let t = MockTransport::new(|req: &Request| match req.opcode {
+            op if op == FuseOpcode::Mknod as u32 => Ok(entry_out(7, libc::S_IFIFO | 0o640)),
+            _ => Ok(Vec::new()),
+        });
+        let sent = t.sent.clone();
+        let mut vfs = VirtioFsImpl::new(FuseProtocol::new(t));
+        vfs.umask(0o027);
+
+        let st = vfs.mknod("/p", libc::S_IFIFO | 0o666, 0).unwrap();
+        assert_eq!(st.inode, 7);
+
+        let sent = sent.borrow();
+        let body = &sent.last().unwrap().body;
+        let word = |i: usize| u32::from_le_bytes(body[i * 4..i * 4 + 4].try_into().unwrap());
+        // fuse_mknod_in: mode, rdev, umask, then the name.
+        assert_eq!(
+            (word(0), word(1), word(2)),
+            (libc::S_IFIFO | 0o640, 0, 0o027)
+        );
+        assert_eq!(&body[16..], b"p\0");
+    }
+
+    #[test]
+    fn fallocate_checks_arguments_before_asking() {
+        let t = flush_server(libc::ENOSYS);
+        let sent = t.sent.clone();
+        let mut vfs = VirtioFsImpl::new(FuseProtocol::new(t));
+        let fd = vfs.open("/f", libc::O_RDWR as u32, 0).unwrap();
+        let ro = vfs.open("/f", libc::O_RDONLY as u32, 0).unwrap();
+        let before = sent.borrow().len();
+
+        let einval = Some(libc::EINVAL);
+        assert_eq!(errno_of(&vfs.allocate(fd, 0, 0, 0).unwrap_err()), einval);
+        assert_eq!(
+            errno_of(&vfs.allocate(fd, OFFSET_MAX, 1, 0).unwrap_err()),
+            einval
+        );
+        let punch = vfs.allocate(fd, 0, 10, FALLOC_FL_PUNCH_HOLE);
+        assert_eq!(errno_of(&punch.unwrap_err()), einval);
+        let read_only = vfs.allocate(ro, 0, 10, 0);
+        assert_eq!(errno_of(&read_only.unwrap_err()), Some(libc::EBADF));
+        assert_eq!(sent.borrow().len(), before);
+
+        vfs.punch_hole(fd, 4096, 8192).unwrap();
+        let sent = sent.borrow();
+        let req = sent.last().unwrap();
+        assert_eq!(req.opcode, FuseOpcode::Fallocate as u32);
+        let u64_at = |at: usize| u64::from_le_bytes(req.body[at..at + 8].try_into().unwrap());
+        assert_eq!((u64_at(8), u64_at(16)), (4096, 8192));
+        let mode = u32::from_le_bytes(req.body[24..28].try_into().unwrap());
+        assert_eq!(mode, FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE);
+    }
+
+    /// "/f", 10000 bytes with a hole at 4096..8192. `lseek` answers LSEEK
+    /// from `(offset, whence)`; GETATTR reports the size.
+    fn sparse_server(
+        mut lseek: impl FnMut(u64, i32) -> Result<u64, i32> + 'static,
+    ) -> MockTransport {
+        MockTransport::new(move |req: &Request| match req.opcode {
+            op if op == FuseOpcode::Lookup as u32 => Ok(entry_out(2, libc::S_IFREG | 0o644)),
+            op if op == FuseOpcode::Open as u32 => Ok(vec![0u8; 16]),
+            op if op == FuseOpcode::Lseek as u32 => {
+                let offset = u64::from_le_bytes(req.body[8..16].try_into().unwrap());
+                let whence = i32::from_le_bytes(req.body[16..20].try_into().unwrap());
+                Ok(lseek(offset, whence)?.to_le_bytes().to_vec())
+            }
+            op if op == FuseOpcode::Getattr as u32 => {
+                let mut out = vec![0u8; 104];
+                out[24..32].copy_from_slice(&10000u64.to_le_bytes());
+                Ok(out)
+            }
+            _ => Ok(Vec::new()),
+        })
+    }
+
+    fn segments(t: MockTransport) -> Vec<std::io::Result<std::ops::Range<u64>>> {
+        let mut vfs = VirtioFsImpl::new(FuseProtocol::new(t));
+        let fd = vfs.open("/f", libc::O_RDONLY as u32, 0).unwrap();
+        vfs.data_segments(fd).collect()
+    }
+
+    #[test]
+    fn data_segments_skip_holes_and_stop_at_enxio() {
+        let t = sparse_server(|off, whence| match (whence, off) {
+            (_, 10000..) => Err(libc::ENXIO),
+            (libc::SEEK_DATA, 4096..8192) => Ok(8192),
+            (libc::SEEK_DATA, _) => Ok(off),
+            (_, ..4096) => Ok(4096),
+            (_, 4096..8192) => Ok(off),
+            _ => Ok(10000),
+        });
+
+        let ranges: Vec<_> = segments(t).into_iter().map(Result::unwrap).collect();
+        assert_eq!(ranges, [0..4096, 8192..10000]);
+    }
+
+    #[test]
+    fn data_segments_fail_when_the_server_does_not_move_on() {
+        let t = sparse_server(|off, _| Ok(off));
+
+        let res = segments(t);
+        assert_eq!(res.len(), 1);
+        assert_eq!(errno_of(res[0].as_ref().unwrap_err()), Some(libc::EIO));
+    }
+
+    #[test]
+    fn data_segments_without_lseek_cover_the_whole_file() {
+        let t = sparse_server(|_, _| Err(libc::ENOSYS));
+
+        let ranges: Vec<_> = segments(t).into_iter().map(Result::unwrap).collect();
+        assert_eq!(ranges.len(), 1);
+        assert_eq!(ranges[0], 0..10000);
+    }
+
+    /// "/src" (nodeid 2, 100 bytes) and "/dst" (nodeid 3); COPY_FILE_RANGE
+    /// fails with `copy_errno` and there is no LSEEK.
+    fn copy_server(copy_errno: i32) -> MockTransport {
+        MockTransport::new(move |req: &Request| match req.opcode {
+            op if op == FuseOpcode::Lookup as u32 => match name_of(req).as_str() {
+                "src" => Ok(entry_out(2, libc::S_IFREG | 0o644)),
+                _ => Ok(entry_out(3, libc::S_IFREG | 0o644)),
+            },
+            op if op == FuseOpcode::Getattr as u32 || op == FuseOpcode::Setattr as u32 => {
+                let mut out = vec![0u8; 104];
+                out[16..24].copy_from_slice(&req.nodeid.to_le_bytes());
+                out[24..32].copy_from_slice(&100u64.to_le_bytes());
+                out[76..80].copy_from_slice(&(libc::S_IFREG | 0o644).to_le_bytes());
+                Ok(out)
+            }
+            op if op == FuseOpcode::Open as u32 => Ok(vec![0u8; 16]),
+            op if op == FuseOpcode::Lseek as u32 => Err(libc::ENOSYS),
+            op if op == FuseOpcode::CopyFileRange as u32 => Err(copy_errno),
+            op if op == FuseOpcode::Read as u32 => {
+                let offset = u64::from_le_bytes(req.body[8..16].try_into().unwrap());
+                Ok(vec![b'x'; 100usize.saturating_sub(offset as usize)])
+            }
+            op if op == FuseOpcode::Write as u32 => {
+                Ok([req.body[16..20].to_vec(), vec![0; 4]].concat())
+            }
+            _ => Ok(Vec::new()),
+        })
+    }
+
+    fn count(sent: &std::cell::RefCell<Vec<Request>>, opcode: FuseOpcode) -> usize {
+        sent.borrow()
+            .iter()
+            .filter(|r| r.opcode == opcode as u32)
+            .count()
+    }
+
+    #[test]
+    fn copy_falls_back_to_read_write_for_good_on_enosys() {
+        let t = copy_server(libc::ENOSYS);
+        let sent = t.sent.clone();
+        let mut vfs = VirtioFsImpl::new(FuseProtocol::new(t));
+
+        assert_eq!(vfs.copy("/src", "/dst").unwrap(), 100);
+        assert_eq!(vfs.copy("/src", "/dst").unwrap(), 100);
+        assert_eq!(count(&sent, FuseOpcode::CopyFileRange), 1);
+        assert_eq!(count(&sent, FuseOpcode::Write), 2);
+    }
+
+    #[test]
+    fn copy_falls_back_to_read_write_per_file_on_exdev() {
+        let t = copy_server(libc::EXDEV);
+        let sent = t.sent.clone();
+        let mut vfs = VirtioFsImpl::new(FuseProtocol::new(t));
+
+        assert_eq!(vfs.copy("/src", "/dst").unwrap(), 100);
+        assert_eq!(vfs.copy("/src", "/dst").unwrap(), 100);
+        assert_eq!(count(&sent, FuseOpcode::CopyFileRange), 2);
+        assert_eq!(count(&sent, FuseOpcode::Write), 2);
+    }
+
+    #[test]
+    fn file_lock_makes_end_inclusive() {
+        let lk = Vfs::file_lock(&(10..20), libc::F_WRLCK as u32).unwrap();
+        assert_eq!((lk.start, lk.end), (10, 19));
+        assert_eq!(lk.typ, libc::F_WRLCK as u32);
+        assert_eq!(lk.pid, std::process::id());
+    }
+
+    #[test]
+    fn file_lock_clamps_to_offset_max() {
+        let lk = Vfs::file_lock(&(0..u64::MAX), libc::F_RDLCK as u32).unwrap();
+        assert_eq!((lk.start, lk.end), (0, OFFSET_MAX));
+
+        let lk = Vfs::file_lock(&(OFFSET_MAX..u64::MAX), libc::F_RDLCK as u32).unwrap();
+        assert_eq!((lk.start, lk.end), (OFFSET_MAX, OFFSET_MAX));
+    }
+
+    #[test]
+    fn file_lock_rejects_empty_and_out_of_range() {
+        let reversed = std::ops::Range { start: 6, end: 5 };
+        for range in [5..5, reversed, OFFSET_MAX + 1..u64::MAX] {
+            let err = Vfs::file_lock(&range, libc::F_RDLCK as u32).unwrap_err();
+            assert_eq!(errno_of(&err), Some(libc::EINVAL), "{:?}", range);
+        }
+    }
+
+    #[test]
+    fn poll_without_notifications_backs_off() {
+        let (t, polls) = poll_server(4);
+        let mut vfs = VirtioFsImpl::new(FuseProtocol::new(t));
+        let fd = vfs.open("/f", libc::O_RDONLY as u32, 0).unwrap();
+
+        let start = std::time::Instant::now();
+        let revents = vfs.poll(fd, libc::POLLIN as u32, None).unwrap();
+
+        assert_eq!(revents, libc::POLLIN as u32);
+        assert_eq!(polls.get(), 4);
+        // 50 + 100 + 200 ms between the four polls.
+        assert!(start.elapsed() >= std::time::Duration::from_millis(350));
+        vfs.close(fd).unwrap();
+    }
+
+    #[test]
+    fn poll_without_notifications_keeps_its_timeout() {
+        let (t, polls) = poll_server(u32::MAX);
+        let mut vfs = VirtioFsImpl::new(FuseProtocol::new(t));
+        let fd = vfs.open("/f", libc::O_RDONLY as u32, 0).unwrap();
+
+        let timeout = std::time::Duration::from_millis(120);
+        let start = std::time::Instant::now();
+        let revents = vfs.poll(fd, libc::POLLIN as u32, Some(timeout)).unwrap();
+
+        assert_eq!(revents, 0);
+        assert!(start.elapsed() >= timeout);
+        assert!(start.elapsed() < std::time::Duration::from_millis(500));
+        // 50 ms, then the 70 ms left, then a last check without waiting.
+        assert_eq!(polls.get(), 3);
+    }
+
+    #[test]
+    fn delete_marks_only_the_name_it_was_opened_by() {
+        let (t, _) = poll_server(1);
+        let mut vfs = VirtioFsImpl::new(FuseProtocol::new(t));
+        let fd = vfs.open("/f", libc::O_RDONLY as u32, 0).unwrap();
+
+        let delete = |parent| FuseNotification::Delete {
+            parent,
+            child: 2,
+            name: "f".into(),
+        };
+        // Same name and inode, but in another directory: a hard link.
+        vfs.apply_notification(&delete(5));
+        assert_eq!(vfs.open_files()[0].1.path, "/f");
+
+        vfs.apply_notification(&delete(1));
+        assert_eq!(vfs.open_files()[0].1.path, "/f (deleted)");
+        vfs.close(fd).unwrap();
+    }
+
+    #[test]
+    fn remove_dir_all_keeps_going_past_failures() {
+        let dir = |name: &str, nodeid| (name.to_string(), nodeid, libc::S_IFDIR | 0o755);
+        let file = |name: &str, nodeid| (name.to_string(), nodeid, libc::S_IFREG | 0o644);
+        let tree = Tree::default();
+        tree.borrow_mut().extend([
+            (1, vec![dir("d", 2)]),
+            (2, vec![file("a", 3), file("locked", 4), dir("sub", 5)]),
+            (5, vec![file("b", 6)]),
+        ]);
+
+        let mut vfs = VirtioFsImpl::new(FuseProtocol::new(tree_server(tree.clone())));
+        let mut reported = Vec::new();
+        let err = vfs
+            .remove_dir_all_with("/d", &mut |path, e| {
+                reported.push((path.to_string(), errno_of(e)))
+            })
+            .unwrap_err();
+
+        assert_eq!(errno_of(&err), Some(libc::EACCES));
+        assert_eq!(
+            reported,
+            [
+                ("/d/locked".to_string(), Some(libc::EACCES)),
+                ("/d".to_string(), Some(libc::ENOTEMPTY)),
+            ]
+        );
+        // Everything else is gone.
+        assert_eq!(tree.borrow()[&2], [file("locked", 4)]);
+    }
+
+    #[test]
+    fn remove_dir_all_unlinks_a_file() {
+        let tree = Tree::default();
+        tree.borrow_mut()
+            .insert(1, vec![("f".to_string(), 2, libc::S_IFREG | 0o644)]);
+
+        let mut vfs = VirtioFsImpl::new(FuseProtocol::new(tree_server(tree.clone())));
+        vfs.remove_dir_all("/f").unwrap();
+        assert!(tree.borrow()[&1].is_empty());
+    }
+
+    #[test]
+    fn remove_dir_all_removes_a_symlink_not_its_target() {
+        let tree = Tree::default();
+        tree.borrow_mut().extend([
+            (
+                1,
+                vec![
+                    ("d".to_string(), 2, libc::S_IFDIR | 0o755),
+                    ("link".to_string(), 3, libc::S_IFLNK | 0o777),
+                ],
+            ),
+            (2, vec![("a".to_string(), 4, libc::S_IFREG | 0o644)]),
+        ]);
+
+        let mut vfs = VirtioFsImpl::new(FuseProtocol::new(tree_server(tree.clone())));
+        vfs.remove_dir_all("/link").unwrap();
+
+        let tree = tree.borrow();
+        assert_eq!(tree[&1], [("d".to_string(), 2, libc::S_IFDIR | 0o755)]);
+        assert_eq!(tree[&2].len(), 1);
+    }
+
+    #[test]
+    fn forget_unused_releases_inodes_the_last_operation_did_not_use() {
+        let file = |name: &str, nodeid| (name.to_string(), nodeid, libc::S_IFREG | 0o644);
+        let tree = Tree::default();
+        tree.borrow_mut()
+            .insert(1, vec![file("a", 2), file("b", 3)]);
+        let t = tree_server(tree);
+        let sent = t.sent.clone();
+        let forgotten = || -> Vec<u64> {
+            sent.borrow()
+                .iter()
+                .filter(|r| r.opcode == FuseOpcode::Forget as u32)
+                .map(|r| r.nodeid)
+                .collect()
+        };
+
+        let mut vfs = VirtioFsImpl::new(FuseProtocol::new(t));
+        vfs.stat("/a").unwrap();
+        vfs.forget_unused().unwrap();
+        assert!(forgotten().is_empty());
+
+        vfs.stat("/b").unwrap();
+        vfs.forget_unused().unwrap();
+        assert_eq!(forgotten(), [2]);
+
+        vfs.forget_unused().unwrap();
+        assert_eq!(forgotten(), [2, 3]);
+    }
 }
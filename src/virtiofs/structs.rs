@@ -1,10 +1,25 @@
+use std::ops::Range;
+
+use crate::protocol::FuseAttr;
+
 pub type Fd = u32;
 
+/// Returned by `VirtioFsImpl::subscribe`, to unsubscribe with later.
+pub type SubscriptionId = u64;
+
 pub struct OpenFile {
+    /// Path the file was opened by, made absolute against the cwd then.
+    pub path: String,
+    /// Directory `path` was looked up in.
+    pub parent: u64,
     pub inode: u64,
     pub fh: u64,
     pub offset: u64,
     pub flags: u32,
+    /// Identifies this fd to the server for FLUSH and POSIX locks.
+    pub lock_owner: u64,
+    /// Set once a flock() was taken, so RELEASE asks the server to drop it.
+    pub flocked: bool,
 }
 
 pub struct FileStat {
@@ -29,8 +44,89 @@ pub struct FileStat {
     pub ctime_nsec: u32,
 }
 
+impl FileStat {
+    pub(crate) fn from_attr(inode: u64, a: &FuseAttr) -> Self {
+        Self {
+            inode,
+            size: a.size,
+            mode: a.mode,
+            nlink: a.nlink,
+            uid: a.uid,
+            gid: a.gid,
+
+            blocks: a.blocks,
+            blksize: a.blksize,
+            rdev: a.rdev,
+
+            atime: a.atime,
+            atime_nsec: a.atimensec,
+
+            mtime: a.mtime,
+            mtime_nsec: a.mtimensec,
+
+            ctime: a.ctime,
+            ctime_nsec: a.ctimensec,
+        }
+    }
+}
+
+/// Filesystem statistics, as returned by statfs(2).
+pub struct FsStat {
+    pub blocks: u64,
+    pub bfree: u64,
+    pub bavail: u64,
+    pub files: u64,
+    pub ffree: u64,
+    pub bsize: u32,
+    pub namelen: u32,
+    pub frsize: u32,
+}
+
 pub struct DirEntryInfo {
     pub name: String,
     pub inode: u64,
     pub mode: u32,
+    /// Full attributes, when the listing came from `readdir_plus`. Not
+    /// filled for entries the server gave no attributes for (often "."
+    /// and "..").
+    pub stat: Option<FileStat>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockKind {
+    /// Shared lock (F_RDLCK / LOCK_SH).
+    Read,
+    /// Exclusive lock (F_WRLCK / LOCK_EX).
+    Write,
+}
+
+/// A lock held by someone else, as reported by `VirtioFsImpl::test_lock`.
+#[derive(Debug, Clone)]
+pub struct LockInfo {
+    pub range: Range<u64>,
+    pub kind: LockKind,
+    pub pid: u32,
+}
+
+/// Target of `VirtioFsImpl::seek`. Unlike `std::io::SeekFrom` this also has
+/// SEEK_DATA and SEEK_HOLE, which need the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeekFrom {
+    Start(u64),
+    Current(i64),
+    End(i64),
+    /// First byte of data at or after the offset (SEEK_DATA).
+    Data(u64),
+    /// First hole at or after the offset (SEEK_HOLE); EOF counts as a hole.
+    Hole(u64),
+}
+
+/// A timestamp for `VirtioFsImpl::set_times`.
+pub enum SetTime {
+    /// The server's current time (FATTR_ATIME_NOW / FATTR_MTIME_NOW).
+    Now,
+    At {
+        sec: u64,
+        nsec: u32,
+    },
 }